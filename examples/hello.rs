@@ -9,7 +9,10 @@ fn main() {
 
     router.add_middleware(LoggingMiddleware {});
     let mut server = warv::server::Server::new();
-    server.add_router(router);
+    if let Err(e) = server.add_router(router) {
+        error!("{}", e);
+        return;
+    }
 
     match server.run(addr) {
         Ok(_) => info!("Clean Exit"),
@@ -17,9 +20,10 @@ fn main() {
     }
 }
 
-fn index(_req: warv::http::Request) -> warv::http::Response {
-    let mut resp = warv::http::Response::ok();
-    resp.insert_header("Content-Type".to_string(), "text/html".to_string());
-    resp.body(format!("Hello World").into());
-    resp
+fn index(_req: warv::http::Request) -> impl warv::http::IntoResponse {
+    (
+        warv::http::StatusCode::OK,
+        [("Content-Type", "text/html")],
+        "Hello World",
+    )
 }
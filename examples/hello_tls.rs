@@ -21,7 +21,10 @@ fn main() {
 
     router.add_middleware(LoggingMiddleware {});
     let mut server = warv::server::Server::new();
-    server.add_router(router);
+    if let Err(e) = server.add_router(router) {
+        error!("{}", e);
+        return;
+    }
 
     match server.run_tls(addr, tlsconfig.into()) {
         Ok(_) => info!("Clean Exit"),
@@ -29,9 +32,10 @@ fn main() {
     }
 }
 
-fn index(_req: warv::http::Request) -> warv::http::Response {
-    let mut resp = warv::http::Response::ok();
-    resp.insert_header("Content-Type".to_string(), "text/html".to_string());
-    resp.body(format!("Hello World").into());
-    resp
+fn index(_req: warv::http::Request) -> impl warv::http::IntoResponse {
+    (
+        warv::http::StatusCode::OK,
+        [("Content-Type", "text/html")],
+        "Hello World",
+    )
 }
@@ -1,33 +1,211 @@
 use crate::http::Response;
+use crate::http::StatusCode;
 use std::error;
 use std::fmt;
+use std::io;
 
-
+/// Errors in a request that can't be parsed, answered before it reaches a router
 #[derive(Debug)]
-pub enum Error {
+pub enum ParseError {
     BadRequest,
+    /// The request line and headers don't fit the request buffer
+    HeaderTooLarge,
+    /// The body is larger than the server accepts
+    PayloadTooLarge,
+    /// The request uses a transfer coding the server doesn't implement
+    UnsupportedTransferEncoding,
+    /// The request is not HTTP/1.0 or HTTP/1.1
+    VersionNotSupported,
+    /// A header line without a colon
+    MissingColon,
+    /// Whitespace between a header name and the colon
+    WhitespaceBeforeColon,
+    /// A header line continuing the previous one (obs-fold), which is no longer allowed
+    ObsoleteLineFolding,
+    /// A header name that is not a token
+    InvalidHeaderName,
+    /// A header value containing control characters
+    InvalidHeaderValue,
+    /// A Content-Length that is not a decimal number
+    InvalidContentLength,
+    /// Several Content-Length headers with different values
+    DuplicateContentLength,
+    /// More than one Host header
+    DuplicateHost,
+    /// An HTTP/1.1 request without a Host header
+    MissingHost,
+    /// A line ended by LF without CR, rejected in strict mode
+    BareLineFeed,
+    /// A Transfer-Encoding not ending with chunked, or with chunked applied more than once
+    InvalidTransferEncoding,
+    /// Both Content-Length and Transfer-Encoding, rejected in strict mode
+    ContentLengthWithTransferEncoding,
+    /// A chunk size that is not a hexadecimal number
+    InvalidChunkSize,
+    /// Chunk data not followed by a line ending, or a malformed chunk line
+    InvalidChunk,
+    /// A request target that is not a valid URI, or in a form the method doesn't allow
+    InvalidTarget,
 }
-impl fmt::Display for Error {
+impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::BadRequest => write!(f, "Bad Request"),
+            ParseError::BadRequest => write!(f, "Bad Request"),
+            ParseError::HeaderTooLarge => write!(f, "Request header too large"),
+            ParseError::PayloadTooLarge => write!(f, "Request body too large"),
+            ParseError::UnsupportedTransferEncoding => write!(f, "Unsupported transfer encoding"),
+            ParseError::VersionNotSupported => write!(f, "HTTP version not supported"),
+            ParseError::MissingColon => write!(f, "Header line without a colon"),
+            ParseError::WhitespaceBeforeColon => write!(f, "Whitespace between header name and colon"),
+            ParseError::ObsoleteLineFolding => write!(f, "Obsolete line folding in header"),
+            ParseError::InvalidHeaderName => write!(f, "Invalid header name"),
+            ParseError::InvalidHeaderValue => write!(f, "Invalid header value"),
+            ParseError::InvalidContentLength => write!(f, "Invalid Content-Length"),
+            ParseError::DuplicateContentLength => write!(f, "Conflicting Content-Length headers"),
+            ParseError::DuplicateHost => write!(f, "Duplicate Host header"),
+            ParseError::MissingHost => write!(f, "Missing Host header"),
+            ParseError::BareLineFeed => write!(f, "Line ending without carriage return"),
+            ParseError::InvalidTransferEncoding => write!(f, "Invalid Transfer-Encoding"),
+            ParseError::ContentLengthWithTransferEncoding => {
+                write!(f, "Both Content-Length and Transfer-Encoding")
+            }
+            ParseError::InvalidChunkSize => write!(f, "Invalid chunk size"),
+            ParseError::InvalidChunk => write!(f, "Invalid chunk"),
+            ParseError::InvalidTarget => write!(f, "Invalid request target"),
         }
     }
 }
 
-impl error::Error for Error {
+impl error::Error for ParseError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        None
+    }
+}
+
+impl ParseError {
+    /// Returns the status the error is answered with
+    pub fn status(&self) -> StatusCode {
         match *self {
-            Error::BadRequest => None,
+            ParseError::BadRequest
+            | ParseError::MissingColon
+            | ParseError::WhitespaceBeforeColon
+            | ParseError::ObsoleteLineFolding
+            | ParseError::InvalidHeaderName
+            | ParseError::InvalidHeaderValue
+            | ParseError::InvalidContentLength
+            | ParseError::DuplicateContentLength
+            | ParseError::DuplicateHost
+            | ParseError::MissingHost
+            | ParseError::BareLineFeed
+            | ParseError::InvalidTransferEncoding
+            | ParseError::ContentLengthWithTransferEncoding
+            | ParseError::InvalidChunkSize
+            | ParseError::InvalidChunk
+            | ParseError::InvalidTarget => StatusCode::BadRequest,
+            ParseError::HeaderTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            ParseError::PayloadTooLarge => StatusCode::RequestEntityTooLarge,
+            ParseError::UnsupportedTransferEncoding => StatusCode::NotImplemented,
+            ParseError::VersionNotSupported => StatusCode::HttpVersionNotSupported,
         }
     }
+    pub fn http_response(&self) -> Response {
+        Response::new(self.status())
+    }
+}
+
+/// An error a handler can return, answered with a response of its status.
+/// The message is shown to the client, except for `Internal` errors.
+#[derive(Debug)]
+pub enum Error {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    UnprocessableEntity(String),
+    /// A server error, its message is only logged
+    Internal(String),
+    /// Any other status
+    Status(StatusCode, String),
 }
 
 impl Error {
-    pub fn http_response(&self) -> Response {
-        let response = match *self {
-            Error::BadRequest => Response::bad_request(),
-        };
-        return response;
+    fn message_str(&self) -> &str {
+        match self {
+            Error::BadRequest(m)
+            | Error::Unauthorized(m)
+            | Error::Forbidden(m)
+            | Error::NotFound(m)
+            | Error::Conflict(m)
+            | Error::PayloadTooLarge(m)
+            | Error::UnsupportedMediaType(m)
+            | Error::UnprocessableEntity(m)
+            | Error::Internal(m)
+            | Error::Status(_, m) => m,
+        }
     }
 }
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.message_str() {
+            "" => write!(f, "{}", ResponseError::status(self).reason().trim()),
+            message => write!(f, "{}", message),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Internal(e.to_string())
+    }
+}
+
+/// An error that can be turned into a response.
+/// Implement it for your own error types to return them from handlers as `Result<T, E>`.
+pub trait ResponseError: fmt::Debug + fmt::Display + Send + Sync + 'static {
+    /// Status of the response, 500 Internal Server Error by default
+    fn status(&self) -> StatusCode {
+        StatusCode::InternalServerError
+    }
+    /// Message shown to the client.
+    /// Server errors only show the reason phrase of their status, so internals aren't leaked.
+    fn message(&self) -> String {
+        let status = self.status();
+        if status.as_u16() >= 500 {
+            status.reason().trim().to_owned()
+        } else {
+            self.to_string()
+        }
+    }
+    /// Builds the response, a JSON, HTML or plain text page depending on the Accept header of the request.
+    /// Not used when the router has an error renderer.
+    fn error_response(&self, accept: Option<&str>) -> Response {
+        Response::error_page_with_message(self.status(), Some(&self.message()), accept)
+    }
+}
+
+impl ResponseError for Error {
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BadRequest,
+            Error::Unauthorized(_) => StatusCode::Unauthorized,
+            Error::Forbidden(_) => StatusCode::Forbidden,
+            Error::NotFound(_) => StatusCode::NotFound,
+            Error::Conflict(_) => StatusCode::Conflict,
+            Error::PayloadTooLarge(_) => StatusCode::RequestEntityTooLarge,
+            Error::UnsupportedMediaType(_) => StatusCode::UnsupporedMediaType,
+            Error::UnprocessableEntity(_) => StatusCode::UnprocessableEntity,
+            Error::Internal(_) => StatusCode::InternalServerError,
+            Error::Status(status, _) => status.clone(),
+        }
+    }
+}
+
+/// Renders the errors returned by the handlers of a router, given the Accept header of the request.
+/// See `Router::set_error_renderer`.
+pub type ErrorRenderer = dyn Fn(&dyn ResponseError, Option<&str>) -> Response + Send + Sync;
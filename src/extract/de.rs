@@ -0,0 +1,422 @@
+//! Deserialization of string pairs, such as path parameters, query strings and form fields, into typed values.
+//!
+//! Values are parsed from their text as the target type requires. Repeated keys
+//! deserialize into sequences, or into their last value for a single value.
+
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Unexpected, Visitor};
+use serde::forward_to_deserialize_any;
+use std::error;
+use std::fmt;
+
+/// Error deserializing string pairs
+#[derive(Debug)]
+pub struct DeError(String);
+
+impl fmt::Display for DeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for DeError {}
+
+impl de::Error for DeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        DeError(msg.to_string())
+    }
+}
+
+/// Deserializes the pairs into `T`, a struct or map keyed by the names, or a single value if there is only one pair
+pub(crate) fn from_pairs<T, I>(pairs: I) -> Result<T, DeError>
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut entries: Vec<(String, Vec<String>)> = Vec::new();
+    for (key, value) in pairs {
+        match entries.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, values)) => values.push(value),
+            None => entries.push((key, vec![value])),
+        }
+    }
+    T::deserialize(Pairs(entries))
+}
+
+/// All pairs, grouped by key in the order the keys first appear
+struct Pairs(Vec<(String, Vec<String>)>);
+
+impl Pairs {
+    /// The values of the only key, for targets that aren't keyed
+    fn single(self) -> Result<Values, DeError> {
+        let mut entries = self.0;
+        match entries.len() {
+            1 => Ok(Values(entries.remove(0).1)),
+            n => Err(DeError(format!("Expected a single value, found {}", n))),
+        }
+    }
+}
+
+macro_rules! single_value {
+    ($($method:ident)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+            self.single()?.$method(visitor)
+        }
+    )*};
+}
+
+impl<'de> de::Deserializer<'de> for Pairs {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_map(MapDeserializer::new(self.0.into_iter().map(|(k, v)| (k, Values(v)))))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        if self.0.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        let values = self.0.into_iter().flat_map(|(_, v)| v).map(Value);
+        visitor.visit_seq(SeqDeserializer::new(values))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.single()?.deserialize_enum(name, variants, visitor)
+    }
+
+    single_value! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_f32 deserialize_f64
+        deserialize_char deserialize_str deserialize_string deserialize_bytes deserialize_byte_buf
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 unit unit_struct tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// The values of one key
+struct Values(Vec<String>);
+
+impl Values {
+    fn last(mut self) -> Value {
+        Value(self.0.pop().unwrap_or_default())
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DeError> for Values {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! last_value {
+    ($($method:ident)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+            self.last().$method(visitor)
+        }
+    )*};
+}
+
+impl<'de> de::Deserializer<'de> for Values {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        self.last().deserialize_any(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_seq(SeqDeserializer::new(self.0.into_iter().map(Value)))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.last().deserialize_enum(name, variants, visitor)
+    }
+
+    last_value! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_f32 deserialize_f64
+        deserialize_char deserialize_str deserialize_string deserialize_bytes deserialize_byte_buf
+        deserialize_option deserialize_unit deserialize_identifier
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 unit_struct tuple_struct map struct ignored_any
+    }
+}
+
+/// A single value, parsed as the target type requires
+struct Value(String);
+
+impl<'de> IntoDeserializer<'de, DeError> for Value {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! parse_value {
+    ($($method:ident => $visit:ident,)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+            match self.0.trim().parse() {
+                Ok(v) => visitor.$visit(v),
+                Err(_) => Err(de::Error::invalid_value(Unexpected::Str(&self.0), &visitor)),
+            }
+        }
+    )*};
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_string(self.0)
+    }
+
+    /// HTML checkboxes are sent as `on`
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0.as_str() {
+            "true" | "on" | "1" => visitor.visit_bool(true),
+            "false" | "off" | "0" | "" => visitor.visit_bool(false),
+            _ => Err(de::Error::invalid_value(Unexpected::Str(&self.0), &visitor)),
+        }
+    }
+
+    /// An empty value is no value
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        if self.0.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_seq(SeqDeserializer::new(std::iter::once(self)))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_enum(IntoDeserializer::<DeError>::into_deserializer(self.0))
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_unit()
+    }
+
+    parse_value! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    forward_to_deserialize_any! {
+        str string bytes byte_buf unit_struct tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::decode_form;
+    use serde::Deserialize;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Sort {
+        Asc,
+        Desc,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Search {
+        q: String,
+        page: u32,
+        offset: i64,
+        ratio: f64,
+        exact: bool,
+        sort: Sort,
+        lang: Option<String>,
+        #[serde(default)]
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn struct_fields_are_parsed() {
+        let search: Search = from_pairs(pairs(&[
+            ("q", "rust"),
+            ("page", " 2 "),
+            ("offset", "-10"),
+            ("ratio", "0.5"),
+            ("exact", "on"),
+            ("sort", "desc"),
+            ("tags", "web"),
+            ("tags", "http"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            search,
+            Search {
+                q: "rust".to_owned(),
+                page: 2,
+                offset: -10,
+                ratio: 0.5,
+                exact: true,
+                sort: Sort::Desc,
+                lang: None,
+                tags: vec!["web".to_owned(), "http".to_owned()],
+            }
+        );
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Filter {
+        lang: Option<String>,
+        limit: Option<u32>,
+        #[serde(default)]
+        tags: Vec<u32>,
+    }
+
+    #[test]
+    fn optional_fields_may_be_missing_or_empty() {
+        let filter: Filter = from_pairs(pairs(&[("lang", ""), ("limit", "5")])).unwrap();
+        assert_eq!(
+            filter,
+            Filter {
+                lang: None,
+                limit: Some(5),
+                tags: Vec::new(),
+            }
+        );
+        let filter: Filter = from_pairs(pairs(&[("lang", "sv"), ("tags", "1")])).unwrap();
+        assert_eq!(filter.lang.as_deref(), Some("sv"));
+        assert_eq!(filter.limit, None);
+        assert_eq!(filter.tags, vec![1]);
+    }
+
+    #[test]
+    fn booleans_accept_checkbox_values() {
+        #[derive(Deserialize)]
+        struct Flag {
+            on: bool,
+        }
+        let values = [("true", true), ("on", true), ("1", true), ("false", false), ("off", false), ("0", false), ("", false)];
+        for (value, expected) in values {
+            let flag: Flag = from_pairs(pairs(&[("on", value)])).unwrap();
+            assert_eq!(flag.on, expected, "{:?}", value);
+        }
+        assert!(from_pairs::<Flag, _>(pairs(&[("on", "yes")])).is_err());
+    }
+
+    #[test]
+    fn repeated_key_keeps_the_last_value_for_a_single_value() {
+        #[derive(Deserialize)]
+        struct Page {
+            page: u32,
+        }
+        let page: Page = from_pairs(pairs(&[("page", "1"), ("page", "3")])).unwrap();
+        assert_eq!(page.page, 3);
+    }
+
+    #[test]
+    fn maps_are_keyed_by_the_names() {
+        let map: HashMap<String, String> = from_pairs(pairs(&[("a", "1"), ("b", "2")])).unwrap();
+        assert_eq!(map.get("a").map(String::as_str), Some("1"));
+        assert_eq!(map.get("b").map(String::as_str), Some("2"));
+        let map: BTreeMap<String, Vec<u32>> = from_pairs(pairs(&[("a", "1"), ("b", "2"), ("a", "3")])).unwrap();
+        assert_eq!(map, BTreeMap::from([("a".to_owned(), vec![1, 3]), ("b".to_owned(), vec![2])]));
+    }
+
+    #[test]
+    fn single_values_and_tuples_are_read_in_order() {
+        assert_eq!(from_pairs::<u64, _>(pairs(&[("id", "42")])).unwrap(), 42);
+        let tuple: (String, u32) = from_pairs(pairs(&[("user", "ann"), ("id", "7")])).unwrap();
+        assert_eq!(tuple, ("ann".to_owned(), 7));
+        let error = from_pairs::<u64, _>(pairs(&[("a", "1"), ("b", "2")])).unwrap_err();
+        assert_eq!(error.to_string(), "Expected a single value, found 2");
+    }
+
+    #[test]
+    fn errors_name_the_problem() {
+        let error = from_pairs::<Search, _>(pairs(&[("q", "rust")])).unwrap_err();
+        assert_eq!(error.to_string(), "missing field `page`");
+        let error = from_pairs::<u32, _>(pairs(&[("id", "abc")])).unwrap_err();
+        assert_eq!(error.to_string(), "invalid value: string \"abc\", expected u32");
+        let error = from_pairs::<u8, _>(pairs(&[("id", "300")])).unwrap_err();
+        assert_eq!(error.to_string(), "invalid value: string \"300\", expected u8");
+        let error = from_pairs::<Sort, _>(pairs(&[("sort", "up")])).unwrap_err();
+        assert_eq!(error.to_string(), "unknown variant `up`, expected `asc` or `desc`");
+    }
+
+    #[test]
+    fn decoded_form_is_deserialized() {
+        #[derive(Deserialize)]
+        struct Comment {
+            name: String,
+            text: String,
+            #[serde(rename = "a b")]
+            spaced: u32,
+        }
+        let comment: Comment = from_pairs(decode_form("name=Ann+Lee&text=50%25+off%21+%C3%A5&a+b=1")).unwrap();
+        assert_eq!(comment.name, "Ann Lee");
+        assert_eq!(comment.text, "50% off! \u{e5}");
+        assert_eq!(comment.spaced, 1);
+    }
+}
@@ -0,0 +1,263 @@
+//! Typed extraction of request data for handler arguments.
+//!
+//! Handlers registered with `Router::add_handler` take up to eight arguments implementing
+//! `FromRequest`. When an argument can't be extracted the handler isn't called and the
+//! request is answered with 400 Bad Request, 415 Unsupported Media Type or 422 Unprocessable Entity.
+
+mod de;
+mod multipart;
+
+use crate::error::Error;
+use crate::http::{CookieJar, Request};
+use crate::state::StateType;
+use serde::de::DeserializeOwned;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::Arc;
+
+pub(crate) use de::from_pairs;
+pub use multipart::{Multipart, MultipartLimits, MultipartReader, Part, PartData, TempFile};
+
+/// A value that can be extracted from a request
+pub trait FromRequest: Sized {
+    fn from_request(req: &Request, state: &crate::state::State) -> Result<Self, Error>;
+    /// State types extraction needs, checked when the router is added to the server
+    fn required_state() -> Vec<StateType> {
+        Vec::new()
+    }
+}
+
+/// The whole request
+impl FromRequest for Request {
+    fn from_request(req: &Request, _state: &crate::state::State) -> Result<Self, Error> {
+        Ok(req.clone())
+    }
+}
+
+/// The shared state of the router
+impl FromRequest for crate::state::State {
+    fn from_request(_req: &Request, state: &crate::state::State) -> Result<Self, Error> {
+        Ok(state.clone())
+    }
+}
+
+/// The value, or `None` when it can't be extracted
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(req: &Request, state: &crate::state::State) -> Result<Self, Error> {
+        Ok(T::from_request(req, state).ok())
+    }
+}
+
+/// The value, or the error extracting it
+impl<T: FromRequest> FromRequest for Result<T, Error> {
+    fn from_request(req: &Request, state: &crate::state::State) -> Result<Self, Error> {
+        Ok(T::from_request(req, state))
+    }
+}
+
+/// Implements `Deref` to the wrapped value for the extractors
+macro_rules! deref {
+    ($($extractor:ident)*) => {$(
+        impl<T> Deref for $extractor<T> {
+            type Target = T;
+
+            fn deref(&self) -> &T {
+                &self.0
+            }
+        }
+    )*};
+}
+
+deref! { Path HostParams Query Form Header Extension }
+
+/// The path parameters of the route, deserialized into a struct or map keyed by parameter name,
+/// a tuple in the order of the route template, or a single value for a route with one parameter.
+#[derive(Debug, Clone)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Path<T> {
+    fn from_request(req: &Request, _state: &crate::state::State) -> Result<Self, Error> {
+        let params = req.uri().pattern_params.clone().unwrap_or_default();
+        from_pairs(params)
+            .map(Path)
+            .map_err(|e| Error::BadRequest(format!("Invalid path parameters: {}", e)))
+    }
+}
+
+/// The labels captured from the host by a virtual host router, such as `{tenant}` in `{tenant}.example.com`,
+/// deserialized like `Path<T>`
+#[derive(Debug, Clone)]
+pub struct HostParams<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for HostParams<T> {
+    fn from_request(req: &Request, _state: &crate::state::State) -> Result<Self, Error> {
+        let params = req.uri().host_params.clone().unwrap_or_default();
+        from_pairs(params)
+            .map(HostParams)
+            .map_err(|e| Error::BadRequest(format!("Invalid host parameters: {}", e)))
+    }
+}
+
+/// The query parameters, deserialized into a struct or map.
+/// Repeated parameters deserialize into sequences.
+#[derive(Debug, Clone)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(req: &Request, _state: &crate::state::State) -> Result<Self, Error> {
+        from_pairs(req.uri().query_pairs().to_vec())
+            .map(Query)
+            .map_err(|e| Error::BadRequest(format!("Invalid query: {}", e)))
+    }
+}
+
+/// The `application/x-www-form-urlencoded` body, deserialized into a struct or map as `Request::form` does.
+/// Use `Multipart` for `multipart/form-data` bodies.
+#[derive(Debug, Clone)]
+pub struct Form<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Form<T> {
+    fn from_request(req: &Request, _state: &crate::state::State) -> Result<Self, Error> {
+        req.form().map(Form)
+    }
+}
+
+/// The JSON body, deserialized into `T` as `Request::json` does.
+/// Returned by a handler it is serialized as an `application/json` response.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "json")]
+deref! { Json }
+
+#[cfg(feature = "json")]
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(req: &Request, _state: &crate::state::State) -> Result<Self, Error> {
+        req.json().map(Json)
+    }
+}
+
+/// A header that can be extracted with `Header<T>`
+pub trait TypedHeader: Sized {
+    /// Name of the header
+    const NAME: &'static str;
+    /// Parses the value of the header, `None` if it is invalid
+    fn decode(value: &str) -> Option<Self>;
+}
+
+/// A typed header, answered with 400 Bad Request when missing or invalid.
+/// Use `Option<Header<T>>` for optional headers.
+#[derive(Debug, Clone)]
+pub struct Header<T>(pub T);
+
+impl<T: TypedHeader> FromRequest for Header<T> {
+    fn from_request(req: &Request, _state: &crate::state::State) -> Result<Self, Error> {
+        let value = req
+            .headers()
+            .get(T::NAME)
+            .ok_or_else(|| Error::BadRequest(format!("Missing header {}", T::NAME)))?;
+        T::decode(value)
+            .map(Header)
+            .ok_or_else(|| Error::BadRequest(format!("Invalid header {}", T::NAME)))
+    }
+}
+
+/// Declares headers extracted as their text
+macro_rules! text_headers {
+    ($($(#[$doc:meta])* $header:ident => $name:literal,)*) => {$(
+        $(#[$doc])*
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct $header(pub String);
+
+        impl TypedHeader for $header {
+            const NAME: &'static str = $name;
+
+            fn decode(value: &str) -> Option<Self> {
+                Some($header(value.to_owned()))
+            }
+        }
+    )*};
+}
+
+text_headers! {
+    /// The Accept header
+    Accept => "Accept",
+    /// The Authorization header
+    Authorization => "Authorization",
+    /// The Content-Type header
+    ContentType => "Content-Type",
+    /// The User-Agent header
+    UserAgent => "User-Agent",
+}
+
+/// The Content-Length header
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentLength(pub usize);
+
+impl TypedHeader for ContentLength {
+    const NAME: &'static str = "Content-Length";
+
+    fn decode(value: &str) -> Option<Self> {
+        value.parse().ok().map(ContentLength)
+    }
+}
+
+/// A value of the shared state of the router, selected by type.
+/// Adding a router whose handlers take a state type it doesn't have fails,
+/// so the state is always there.
+#[derive(Debug)]
+pub struct State<T>(pub Arc<T>);
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Send + Sync + 'static> FromRequest for State<T> {
+    fn from_request(_req: &Request, state: &crate::state::State) -> Result<Self, Error> {
+        state.inner::<T>().map(State).ok_or_else(|| {
+            Error::Internal(format!("No state of type {}", std::any::type_name::<T>()))
+        })
+    }
+
+    fn required_state() -> Vec<StateType> {
+        vec![StateType::of::<T>()]
+    }
+}
+
+/// A value a middleware attached to the request, see `Request::extensions`.
+/// A missing value is answered with 500 Internal Server Error,
+/// use `Option<Extension<T>>` for values that are not always attached.
+#[derive(Debug, Clone)]
+pub struct Extension<T>(pub T);
+
+impl<T: Clone + Send + Sync + 'static> FromRequest for Extension<T> {
+    fn from_request(req: &Request, _state: &crate::state::State) -> Result<Self, Error> {
+        req.extensions().get::<T>().cloned().map(Extension).ok_or_else(|| {
+            Error::Internal(format!("No extension of type {}", std::any::type_name::<T>()))
+        })
+    }
+}
+
+/// The cookies sent with the request
+impl FromRequest for CookieJar {
+    fn from_request(req: &Request, _state: &crate::state::State) -> Result<Self, Error> {
+        Ok(req.cookies())
+    }
+}
+
+/// The address of the client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RemoteAddr(pub SocketAddr);
+
+impl FromRequest for RemoteAddr {
+    fn from_request(req: &Request, _state: &crate::state::State) -> Result<Self, Error> {
+        req.remote_addr()
+            .map(RemoteAddr)
+            .ok_or_else(|| Error::Internal("The remote address is unknown".to_owned()))
+    }
+}
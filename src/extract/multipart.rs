@@ -0,0 +1,789 @@
+//! Parsing of `multipart/form-data` bodies (RFC 7578), as sent by HTML forms uploading files.
+//!
+//! `MultipartReader` reads the parts one at a time from any `Read`, buffering little more than
+//! the part being read. File parts larger than the memory limit are written to temporary files.
+//! The body of a request is already held in memory by the server, so `Multipart` and
+//! `MultipartReader::from_request` only avoid copying large files once more; the temporary files
+//! bound the memory used when reading from other sources, such as a file or a stream.
+
+use crate::error::Error;
+use crate::extract::{from_pairs, FromRequest};
+use crate::http::percent::decode;
+use crate::http::{HeaderMap, Request};
+use serde::de::DeserializeOwned;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Size of the reads from the body
+const CHUNK: usize = 8 * 1024;
+/// Largest header section of a part
+const MAX_PART_HEADERS: usize = 8 * 1024;
+
+/// Limits of a multipart body, exceeding one is answered with 413 Payload Too Large
+#[derive(Debug, Clone, Copy)]
+pub struct MultipartLimits {
+    /// Largest body, default 4 MB
+    pub total: usize,
+    /// Largest file, default 4 MB
+    pub file: usize,
+    /// Largest field that is not a file, default 64 KB
+    pub field: usize,
+    /// Most parts, default 100
+    pub parts: usize,
+    /// Largest file kept in memory, larger files are written to a temporary file. Default 64 KB
+    pub memory: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits {
+            total: 4 * 1024 * 1024,
+            file: 4 * 1024 * 1024,
+            field: 64 * 1024,
+            parts: 100,
+            memory: 64 * 1024,
+        }
+    }
+}
+
+/// A temporary file holding an uploaded file, removed when dropped unless persisted
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    len: u64,
+}
+
+impl TempFile {
+    /// Creates a new empty file in the temporary directory, only readable by the owner
+    fn create() -> io::Result<(TempFile, File)> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        loop {
+            let name = format!("warv-upload-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+            let path = std::env::temp_dir().join(name);
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&path) {
+                Ok(file) => return Ok((TempFile { path, len: 0 }, file)),
+                // Left behind by an earlier process with the same id
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    /// Returns the path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Returns the size of the file
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    /// Returns true if the file is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Opens the file for reading
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+    /// Moves the file to `to`, keeping it after the upload is dropped
+    pub fn persist<P: AsRef<Path>>(mut self, to: P) -> io::Result<()> {
+        // Renaming fails across file systems
+        if fs::rename(&self.path, to.as_ref()).is_err() {
+            fs::copy(&self.path, to.as_ref())?;
+            let _ = fs::remove_file(&self.path);
+        }
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The content of a part
+#[derive(Debug)]
+pub enum PartData {
+    Memory(Vec<u8>),
+    /// A file larger than `MultipartLimits::memory`
+    File(TempFile),
+}
+
+/// A part of a multipart body, a form field or an uploaded file
+#[derive(Debug)]
+pub struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    headers: HeaderMap,
+    data: PartData,
+}
+
+impl Part {
+    /// Returns the name of the form field
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Returns the name of the uploaded file as sent by the client.
+    /// It may contain path separators and shouldn't be used as a path as it is.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+    /// Returns the Content-Type of the part
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+    /// Returns the headers of the part
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+    /// Returns true if the part is an uploaded file
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+    /// Returns the content
+    pub fn data(&self) -> &PartData {
+        &self.data
+    }
+    /// Returns the content, to keep a temporary file with `TempFile::persist`
+    pub fn into_data(self) -> PartData {
+        self.data
+    }
+    /// Returns the size of the content
+    pub fn len(&self) -> u64 {
+        match &self.data {
+            PartData::Memory(data) => data.len() as u64,
+            PartData::File(file) => file.len(),
+        }
+    }
+    /// Returns true if the content is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Returns the content as text, `None` if it is in a file or not UTF-8
+    pub fn text(&self) -> Option<&str> {
+        match &self.data {
+            PartData::Memory(data) => std::str::from_utf8(data).ok(),
+            PartData::File(_) => None,
+        }
+    }
+    /// Returns the content, reading it from the temporary file if needed
+    pub fn bytes(&self) -> io::Result<Vec<u8>> {
+        match &self.data {
+            PartData::Memory(data) => Ok(data.clone()),
+            PartData::File(file) => fs::read(file.path()),
+        }
+    }
+}
+
+/// Reads the parts of a `multipart/form-data` body in order
+pub struct MultipartReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// `CRLF--boundary`, ending the part before it
+    delimiter: Vec<u8>,
+    limits: MultipartLimits,
+    read: usize,
+    parts: usize,
+    started: bool,
+    done: bool,
+    eof: bool,
+}
+
+impl<'a> MultipartReader<&'a [u8]> {
+    /// Reads the body of the request, as buffered by the server up to `Server::max_body_size`.
+    /// Fails with 415 if it isn't `multipart/form-data` and with 400 if it has no boundary.
+    pub fn from_request(req: &'a Request, limits: MultipartLimits) -> Result<Self, Error> {
+        if req.media_type().as_deref() != Some("multipart/form-data") {
+            return Err(Error::UnsupportedMediaType("Expected multipart/form-data".to_owned()));
+        }
+        let content_type = req.headers().get("Content-Type").unwrap_or("");
+        let params = content_type.split_once(';').map_or("", |(_, params)| params);
+        let boundary = parameters(params)
+            .into_iter()
+            .find(|(name, _)| name == "boundary")
+            .map(|(_, boundary)| boundary)
+            .filter(|b| (1..=70).contains(&b.len()))
+            .ok_or_else(|| Error::BadRequest("Missing multipart boundary".to_owned()))?;
+        Ok(MultipartReader::new(&req.body[..], &boundary, limits))
+    }
+}
+
+impl<R: Read> MultipartReader<R> {
+    pub fn new(reader: R, boundary: &str, limits: MultipartLimits) -> Self {
+        MultipartReader {
+            reader,
+            // The first delimiter has no line break before it
+            buf: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            limits,
+            read: 0,
+            parts: 0,
+            started: false,
+            done: false,
+            eof: false,
+        }
+    }
+
+    /// Reads the next part, `None` after the last one.
+    /// Fails with 400 if the body is malformed and with 413 if it exceeds a limit.
+    pub fn next_part(&mut self) -> Result<Option<Part>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+        if !self.started {
+            // Anything before the first delimiter is a preamble to ignore
+            self.read_until_delimiter(|_| Ok(()))?;
+            self.started = true;
+        }
+        // The delimiter is followed by `--` after the last part, then optional whitespace and a line break
+        let rest = self.take_until(b"\r\n", 256).or_else(|e| {
+            // The line break after the last delimiter is optional
+            if self.buf.starts_with(b"--") && self.eof {
+                Ok(b"--".to_vec())
+            } else {
+                Err(e)
+            }
+        })?;
+        if rest.starts_with(b"--") {
+            self.done = true;
+            return Ok(None);
+        }
+        if !rest.iter().all(|&b| b == b' ' || b == b'\t') {
+            return Err(malformed());
+        }
+
+        self.parts += 1;
+        if self.parts > self.limits.parts {
+            return Err(Error::PayloadTooLarge(format!("More than {} parts", self.limits.parts)));
+        }
+        let headers = self.read_headers()?;
+        let (name, filename) = headers
+            .get("Content-Disposition")
+            .and_then(content_disposition)
+            .ok_or_else(|| Error::BadRequest("Part without a form-data Content-Disposition".to_owned()))?;
+
+        let mut sink = match filename {
+            Some(_) => Sink::new(self.limits.file, Some(self.limits.memory)),
+            None => Sink::new(self.limits.field, None),
+        };
+        self.read_until_delimiter(|bytes| sink.write(bytes))?;
+        Ok(Some(Part {
+            name,
+            filename,
+            content_type: headers.get("Content-Type").map(str::to_owned),
+            data: sink.finish()?,
+            headers,
+        }))
+    }
+
+    /// Reads the header section of a part
+    fn read_headers(&mut self) -> Result<HeaderMap, Error> {
+        let mut headers = HeaderMap::new();
+        // A part without headers starts with the empty line
+        if !self.buf.starts_with(b"\r\n") {
+            while self.buf.len() < 2 && self.fill()? {}
+        }
+        if self.buf.starts_with(b"\r\n") {
+            self.buf.drain(..2);
+            return Ok(headers);
+        }
+        let section = self.take_until(b"\r\n\r\n", MAX_PART_HEADERS)?;
+        let section = String::from_utf8_lossy(&section);
+        for line in section.split("\r\n") {
+            let (name, value) = line.split_once(':').ok_or_else(malformed)?;
+            headers
+                .try_append(name, value.trim())
+                .map_err(|e| Error::BadRequest(e.to_string()))?;
+        }
+        Ok(headers)
+    }
+
+    /// Passes the bytes up to the next delimiter to `write`, consuming the delimiter
+    fn read_until_delimiter(&mut self, mut write: impl FnMut(&[u8]) -> Result<(), Error>) -> Result<(), Error> {
+        loop {
+            if let Some(i) = find(&self.buf, &self.delimiter) {
+                write(&self.buf[..i])?;
+                self.buf.drain(..i + self.delimiter.len());
+                return Ok(());
+            }
+            // The end of the buffer may be the start of the delimiter
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                let n = self.buf.len() - keep;
+                write(&self.buf[..n])?;
+                self.buf.drain(..n);
+            }
+            if !self.fill()? {
+                return Err(malformed());
+            }
+        }
+    }
+
+    /// Returns the bytes before `pattern`, consuming the pattern.
+    /// Fails if the pattern doesn't follow within `max` bytes.
+    fn take_until(&mut self, pattern: &[u8], max: usize) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(i) = find(&self.buf, pattern).filter(|&i| i <= max) {
+                let taken = self.buf[..i].to_vec();
+                self.buf.drain(..i + pattern.len());
+                return Ok(taken);
+            }
+            if self.buf.len() > max + pattern.len() || !self.fill()? {
+                return Err(malformed());
+            }
+        }
+    }
+
+    /// Reads more of the body into the buffer, false at its end
+    fn fill(&mut self) -> Result<bool, Error> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut chunk = [0; CHUNK];
+        let n = loop {
+            match self.reader.read(&mut chunk) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        if n == 0 {
+            self.eof = true;
+            return Ok(false);
+        }
+        self.read += n;
+        if self.read > self.limits.total {
+            return Err(Error::PayloadTooLarge(format!(
+                "Multipart body larger than {} bytes",
+                self.limits.total
+            )));
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(true)
+    }
+}
+
+/// Collects the content of a part, moving it to a temporary file once it exceeds `memory`
+struct Sink {
+    data: Vec<u8>,
+    file: Option<(TempFile, File)>,
+    len: usize,
+    limit: usize,
+    memory: Option<usize>,
+}
+
+impl Sink {
+    fn new(limit: usize, memory: Option<usize>) -> Self {
+        Sink {
+            data: Vec::new(),
+            file: None,
+            len: 0,
+            limit,
+            memory,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.len += bytes.len();
+        if self.len > self.limit {
+            return Err(Error::PayloadTooLarge(format!("Part larger than {} bytes", self.limit)));
+        }
+        if self.file.is_none() && self.memory.is_some_and(|memory| self.len > memory) {
+            let (temp, mut file) = TempFile::create()?;
+            file.write_all(&self.data)?;
+            self.data = Vec::new();
+            self.file = Some((temp, file));
+        }
+        match &mut self.file {
+            Some((_, file)) => file.write_all(bytes)?,
+            None => self.data.extend_from_slice(bytes),
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<PartData, Error> {
+        match self.file {
+            Some((mut temp, mut file)) => {
+                file.flush()?;
+                temp.len = self.len as u64;
+                Ok(PartData::File(temp))
+            }
+            None => Ok(PartData::Memory(self.data)),
+        }
+    }
+}
+
+/// A `multipart/form-data` body read with the default `MultipartLimits`.
+/// Use `MultipartReader` for other limits or to handle the parts as they are read.
+#[derive(Debug, Default)]
+pub struct Multipart {
+    parts: Vec<Part>,
+}
+
+impl Multipart {
+    /// Returns the text of the first field with the name that is not a file
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.parts
+            .iter()
+            .find(|p| p.name == name && !p.is_file())
+            .and_then(Part::text)
+    }
+    /// Returns the first file uploaded with the name
+    pub fn file(&self, name: &str) -> Option<&Part> {
+        self.parts.iter().find(|p| p.name == name && p.is_file())
+    }
+    /// Returns all parts, in order
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+    pub fn into_parts(self) -> Vec<Part> {
+        self.parts
+    }
+    /// Deserializes the fields that are not files into a struct or map, like `Form<T>`.
+    /// Fails with 422 if they don't match `T`.
+    pub fn fields<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        let fields = self
+            .parts
+            .iter()
+            .filter(|p| !p.is_file())
+            .filter_map(|p| Some((p.name.clone(), p.text()?.to_owned())));
+        from_pairs(fields).map_err(|e| Error::UnprocessableEntity(format!("Invalid form: {}", e)))
+    }
+}
+
+impl FromRequest for Multipart {
+    fn from_request(req: &Request, _state: &crate::state::State) -> Result<Self, Error> {
+        let mut reader = MultipartReader::from_request(req, MultipartLimits::default())?;
+        let mut parts = Vec::new();
+        while let Some(part) = reader.next_part()? {
+            parts.push(part);
+        }
+        Ok(Multipart { parts })
+    }
+}
+
+fn malformed() -> Error {
+    Error::BadRequest("Malformed multipart body".to_owned())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Parses `form-data; name="field"; filename="file.txt"` into the name and filename.
+/// `filename*` (RFC 5987) takes precedence over `filename`.
+fn content_disposition(value: &str) -> Option<(String, Option<String>)> {
+    let (kind, params) = value.split_once(';').unwrap_or((value, ""));
+    if !kind.trim().eq_ignore_ascii_case("form-data") {
+        return None;
+    }
+    let params = parameters(params);
+    let param = |name: &str| params.iter().find(|(n, _)| n == name).map(|(_, v)| v.clone());
+    let extended = param("filename*").and_then(|v| {
+        let (charset, encoded) = v.split_once('\'')?;
+        let (_language, encoded) = encoded.split_once('\'')?;
+        charset.eq_ignore_ascii_case("utf-8").then(|| decode(encoded))?
+    });
+    Some((param("name")?, extended.or_else(|| param("filename"))))
+}
+
+/// Parses `; name=value` parameters, with values possibly quoted. Names are lowercased.
+/// Parsing stops at a quoted value missing its closing quote.
+fn parameters(mut params: &str) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    loop {
+        params = params.trim_start().trim_start_matches(';').trim_start();
+        let Some((name, rest)) = params.split_once('=') else {
+            return parsed;
+        };
+        let rest = rest.trim_start();
+        let (value, rest) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut end = None;
+                let mut chars = quoted.char_indices();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = Some(i + 1);
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                let Some(end) = end else {
+                    return parsed;
+                };
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = rest.find(';').unwrap_or(rest.len());
+                (rest[..end].trim().to_owned(), &rest[end..])
+            }
+        };
+        parsed.push((name.trim().to_ascii_lowercase(), value));
+        params = rest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    const BOUNDARY: &str = "XyZ";
+
+    /// A field `a` and a file `f`
+    const BODY: &[u8] = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nhello world\r\n--XyZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\nContent-Type: text/plain\r\n\r\nfile content here\r\n--XyZ--\r\n";
+
+    fn read_all<R: Read>(reader: R, limits: MultipartLimits) -> Result<Vec<Part>, Error> {
+        let mut reader = MultipartReader::new(reader, BOUNDARY, limits);
+        let mut parts = Vec::new();
+        while let Some(part) = reader.next_part()? {
+            parts.push(part);
+        }
+        Ok(parts)
+    }
+
+    /// A named body and a check of the error it is answered with
+    type Malformed = (&'static str, &'static [u8], fn(&Error) -> bool);
+
+    const MALFORMED: &[Malformed] = &[
+        ("No delimiter", b"hello", |e| matches!(e, Error::BadRequest(_))),
+        (
+            "No closing delimiter",
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nhello",
+            |e| matches!(e, Error::BadRequest(_)),
+        ),
+        (
+            "Text after the delimiter",
+            b"--XyZx\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nhello\r\n--XyZ--",
+            |e| matches!(e, Error::BadRequest(_)),
+        ),
+        (
+            "Header line without a colon",
+            b"--XyZ\r\nContent-Disposition form-data\r\n\r\nhello\r\n--XyZ--",
+            |e| matches!(e, Error::BadRequest(_)),
+        ),
+        (
+            "Headers never ending",
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\nhello\r\n--XyZ--",
+            |e| matches!(e, Error::BadRequest(_)),
+        ),
+        (
+            "No Content-Disposition",
+            b"--XyZ\r\nContent-Type: text/plain\r\n\r\nhello\r\n--XyZ--",
+            |e| matches!(e, Error::BadRequest(_)),
+        ),
+        (
+            "Content-Disposition other than form-data",
+            b"--XyZ\r\nContent-Disposition: attachment; name=\"a\"\r\n\r\nhello\r\n--XyZ--",
+            |e| matches!(e, Error::BadRequest(_)),
+        ),
+        (
+            "Unterminated quoted name",
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"a\r\n\r\nhello\r\n--XyZ--",
+            |e| matches!(e, Error::BadRequest(_)),
+        ),
+    ];
+
+    #[test]
+    fn malformed_bodies_are_rejected() {
+        for (name, body, expected) in MALFORMED {
+            match read_all(*body, MultipartLimits::default()) {
+                Err(e) => assert!(expected(&e), "{}: unexpected error {:?}", name, e),
+                Ok(_) => panic!("{}: accepted", name),
+            }
+        }
+    }
+
+    /// A named limit lowered below what `BODY` needs
+    type Limit = (&'static str, fn(&mut MultipartLimits));
+
+    const LIMITS: &[Limit] = &[
+        ("total", |l| l.total = BODY.len() - 1),
+        ("file", |l| l.file = 16),
+        ("field", |l| l.field = 10),
+        ("parts", |l| l.parts = 1),
+    ];
+
+    #[test]
+    fn exceeded_limits_are_rejected() {
+        for (name, lower) in LIMITS {
+            let mut limits = MultipartLimits::default();
+            lower(&mut limits);
+            match read_all(BODY, limits) {
+                Err(e) => assert!(matches!(e, Error::PayloadTooLarge(_)), "{}: unexpected error {:?}", name, e),
+                Ok(_) => panic!("{}: accepted", name),
+            }
+        }
+    }
+
+    #[test]
+    fn fields_and_files_are_read() {
+        let parts = read_all(BODY, MultipartLimits::default()).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name(), "a");
+        assert_eq!(parts[0].text(), Some("hello world"));
+        assert!(!parts[0].is_file());
+        assert_eq!(parts[1].filename(), Some("f.txt"));
+        assert_eq!(parts[1].content_type(), Some("text/plain"));
+        assert_eq!(parts[1].text(), Some("file content here"));
+    }
+
+    #[test]
+    fn preamble_and_epilogue_are_ignored() {
+        let body = b"A preamble mentioning --XyZ\r\n--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nhello\r\n--XyZ--\r\nAn epilogue";
+        let parts = read_all(&body[..], MultipartLimits::default()).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].text(), Some("hello"));
+    }
+
+    #[test]
+    fn final_line_break_is_optional() {
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nhello\r\n--XyZ--";
+        let parts = read_all(&body[..], MultipartLimits::default()).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].text(), Some("hello"));
+    }
+
+    #[test]
+    fn delimiter_split_across_reads_is_found() {
+        let head = b"--XyZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"f.bin\"\r\n\r\n";
+        let delimiter = b"\r\n--XyZ";
+        // The delimiter starts right after the first read, then straddles it, then fits in it
+        for shift in 0..=delimiter.len() + 1 {
+            // Ends with the start of a delimiter, which is content
+            let mut data = vec![b'x'; CHUNK - head.len() - shift - 5];
+            data.extend_from_slice(b"\r\n--X");
+            let mut body = head.to_vec();
+            body.extend_from_slice(&data);
+            body.extend_from_slice(b"\r\n--XyZ--\r\n");
+            let parts = read_all(&body[..], MultipartLimits::default()).unwrap();
+            assert_eq!(parts.len(), 1, "shift {}", shift);
+            assert_eq!(parts[0].bytes().unwrap(), data, "shift {}", shift);
+        }
+    }
+
+    /// Hands out the bytes one read at a time
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((&first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn body_read_a_byte_at_a_time_is_parsed() {
+        let parts = read_all(Trickle(BODY), MultipartLimits::default()).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].text(), Some("hello world"));
+        assert_eq!(parts[1].text(), Some("file content here"));
+    }
+
+    #[test]
+    fn extended_filename_takes_precedence() {
+        let disposition = |params: &str| content_disposition(&format!("form-data; name=\"f\"; {}", params)).unwrap().1;
+        assert_eq!(
+            disposition("filename=\"rates.txt\"; filename*=UTF-8''%E2%82%AC%20rates.txt").as_deref(),
+            Some("€ rates.txt")
+        );
+        // Only UTF-8 is decoded, other charsets fall back to `filename`
+        assert_eq!(
+            disposition("filename=\"rates.txt\"; filename*=ISO-8859-1''%A4%20rates.txt").as_deref(),
+            Some("rates.txt")
+        );
+    }
+
+    #[test]
+    fn unterminated_quoted_parameter_is_dropped() {
+        assert_eq!(
+            parameters("; name=\"a\"; filename=\"f.txt"),
+            vec![("name".to_owned(), "a".to_owned())]
+        );
+        assert_eq!(
+            parameters("; name=\"a;\\\"b\"; x=y"),
+            vec![("name".to_owned(), "a;\"b".to_owned()), ("x".to_owned(), "y".to_owned())]
+        );
+    }
+
+    #[test]
+    fn large_file_is_written_to_a_temporary_file_removed_when_dropped() {
+        let limits = MultipartLimits {
+            memory: 4,
+            ..MultipartLimits::default()
+        };
+        let mut parts = read_all(BODY, limits).unwrap();
+        // Fields are never written to files
+        assert!(matches!(parts[0].data(), PartData::Memory(_)));
+        let file = parts.pop().unwrap();
+        let PartData::File(temp) = file.data() else {
+            panic!("file kept in memory");
+        };
+        let path = temp.path().to_owned();
+        assert_eq!(temp.len(), 17);
+        assert_eq!(fs::read(&path).unwrap(), b"file content here");
+        #[cfg(unix)]
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&path).unwrap().permissions()) & 0o777,
+            0o600
+        );
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn persisted_file_is_kept() {
+        let limits = MultipartLimits {
+            memory: 4,
+            ..MultipartLimits::default()
+        };
+        let file = read_all(BODY, limits).unwrap().pop().unwrap();
+        let PartData::File(temp) = file.into_data() else {
+            panic!("file kept in memory");
+        };
+        let from = temp.path().to_owned();
+        let to = std::env::temp_dir().join(format!("warv-persisted-{}", std::process::id()));
+        temp.persist(&to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), b"file content here");
+        fs::remove_file(&to).unwrap();
+    }
+
+    #[test]
+    fn request_boundary_is_read_from_the_content_type() {
+        let mut req = Request::new(Method::POST);
+        req.headers_mut()
+            .try_insert("Content-Type", "multipart/form-data; boundary=\"XyZ\"")
+            .unwrap();
+        req.body = BODY.to_vec();
+        let multipart = Multipart::from_request(&req, &crate::state::State::new()).unwrap();
+        assert_eq!(multipart.field("a"), Some("hello world"));
+        assert_eq!(multipart.file("f").and_then(Part::filename), Some("f.txt"));
+
+        req.headers_mut()
+            .try_insert("Content-Type", "multipart/form-data; boundary=\"XyZ")
+            .unwrap();
+        assert!(matches!(
+            MultipartReader::from_request(&req, MultipartLimits::default()),
+            Err(Error::BadRequest(_))
+        ));
+    }
+}
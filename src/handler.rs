@@ -1,6 +1,9 @@
-use crate::state::State;
+use crate::extract::FromRequest;
+use crate::state::{State, StateType};
+use crate::http::IntoResponse;
 use crate::http::Request;
 use crate::http::Response;
+use std::marker::PhantomData;
 
 
 pub trait Handler: Send + Sync {
@@ -9,6 +12,10 @@ pub trait Handler: Send + Sync {
         req: Request,
         state: State,
     ) -> Response;
+    /// State types the handler needs, checked when the router is added to the server
+    fn required_state(&self) -> Vec<StateType> {
+        Vec::new()
+    }
 }
 
 /// Enum for Handler types
@@ -49,3 +56,73 @@ impl Handler for HandlerType {
         }
     }
 }
+
+/// A function taking arguments extracted from the request, see `Router::add_handler`.
+/// Implemented for functions of up to eight arguments implementing `FromRequest`,
+/// returning anything implementing `IntoResponse`.
+pub trait ExtractHandler<Args>: Send + Sync + 'static {
+    fn call(&self, req: Request, state: State) -> Response;
+    /// State types the arguments need
+    fn required_state(&self) -> Vec<StateType>;
+}
+
+macro_rules! extract_handler {
+    ($($arg:ident),*) => {
+        #[allow(non_snake_case, unused_variables)]
+        impl<F, R, $($arg,)*> ExtractHandler<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest,)*
+        {
+            fn call(&self, req: Request, state: State) -> Response {
+                $(
+                    let $arg = match $arg::from_request(&req, &state) {
+                        Ok(value) => value,
+                        Err(e) => return Response::from_error(e),
+                    };
+                )*
+                self($($arg),*).into_response()
+            }
+
+            fn required_state(&self) -> Vec<StateType> {
+                std::iter::empty()$(.chain($arg::required_state()))*.collect()
+            }
+        }
+    };
+}
+
+extract_handler!();
+extract_handler!(A1);
+extract_handler!(A1, A2);
+extract_handler!(A1, A2, A3);
+extract_handler!(A1, A2, A3, A4);
+extract_handler!(A1, A2, A3, A4, A5);
+extract_handler!(A1, A2, A3, A4, A5, A6);
+extract_handler!(A1, A2, A3, A4, A5, A6, A7);
+extract_handler!(A1, A2, A3, A4, A5, A6, A7, A8);
+
+/// Adapts an `ExtractHandler` to a `Handler`
+pub(crate) struct Extract<H, Args> {
+    handler: H,
+    args: PhantomData<fn() -> Args>,
+}
+
+impl<H, Args> Extract<H, Args> {
+    pub(crate) fn new(handler: H) -> Self {
+        Extract {
+            handler,
+            args: PhantomData,
+        }
+    }
+}
+
+impl<H: ExtractHandler<Args>, Args: 'static> Handler for Extract<H, Args> {
+    fn handle(&self, req: Request, state: State) -> Response {
+        self.handler.call(req, state)
+    }
+
+    fn required_state(&self) -> Vec<StateType> {
+        self.handler.required_state()
+    }
+}
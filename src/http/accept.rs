@@ -0,0 +1,51 @@
+/// Picks the media type of `offers` the client prefers according to its Accept header.
+/// Offers are listed in the server's order of preference, which also breaks ties.
+/// Without an Accept header the first offer is returned, `None` if the client accepts none of them.
+pub fn preferred_media_type<'a>(accept: Option<&str>, offers: &[&'a str]) -> Option<&'a str> {
+    let accept = match accept {
+        Some(a) if !a.trim().is_empty() => a,
+        _ => return offers.first().copied(),
+    };
+    let ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let media = params.next()?.trim();
+            let quality = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((media, quality))
+        })
+        .collect();
+
+    let mut best: Option<(&str, f32)> = None;
+    for offer in offers {
+        let (kind, _) = offer.split_once('/').unwrap_or((offer, ""));
+        // The most specific matching range decides the quality of the offer
+        let quality = ranges
+            .iter()
+            .filter_map(|(media, q)| {
+                let specificity = if media.eq_ignore_ascii_case(offer) {
+                    2
+                } else if media
+                    .strip_suffix("/*")
+                    .is_some_and(|k| k.eq_ignore_ascii_case(kind))
+                {
+                    1
+                } else if *media == "*/*" {
+                    0
+                } else {
+                    return None;
+                };
+                Some((specificity, *q))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, q)| q)
+            .unwrap_or(0.0);
+        if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((offer, quality));
+        }
+    }
+    best.map(|(offer, _)| offer)
+}
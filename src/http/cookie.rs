@@ -0,0 +1,432 @@
+//! Cookies (RFC 6265): the jar of cookies sent with a request, and cookies to set with a response.
+//!
+//! Cookies can be signed, so the client can read but not change them, or made private,
+//! encrypted so the client can neither read nor change them. Both use a `Key` derived from a server secret.
+
+use crate::http::{is_tchar, HeaderMap};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::error;
+use std::fmt;
+use std::time::Duration;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Size of the AES-GCM nonce prefixed to private cookies
+const NONCE_LEN: usize = 12;
+
+/// The SameSite attribute of a cookie
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Sent with cross-site requests too, requires `Secure`
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SameSite::Strict => f.write_str("Strict"),
+            SameSite::Lax => f.write_str("Lax"),
+            SameSite::None => f.write_str("None"),
+        }
+    }
+}
+
+/// A cookie to set with `Response::add_cookie`, written as a Set-Cookie header
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<DateTime<Utc>>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Cookie {
+            name: name.into(),
+            value: value.into(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+    /// Sets the path the cookie is sent for
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+    /// Sets the domain the cookie is sent to, including its subdomains
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+    /// Sets how long the client keeps the cookie, in whole seconds
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+    /// Sets when the client discards the cookie, `max_age` takes precedence
+    pub fn expires(mut self, expires: DateTime<Utc>) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+    /// Sets whether the cookie is only sent over HTTPS
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+    /// Sets whether the cookie is hidden from scripts
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+    /// Signs the value, read back with `CookieJar::get_signed`.
+    /// The client can read the value but not change it.
+    pub fn signed(mut self, key: &Key) -> Self {
+        self.value = key.sign(&self.name, &self.value);
+        self
+    }
+    /// Encrypts the value, read back with `CookieJar::get_private`.
+    /// The client can neither read nor change the value.
+    pub fn private(mut self, key: &Key) -> Self {
+        self.value = key.encrypt(&self.name, &self.value);
+        self
+    }
+    /// Turns the cookie into one removing it from the client.
+    /// The path and domain must be the ones the cookie was set with.
+    pub fn removal(mut self) -> Self {
+        self.value = String::new();
+        self.max_age = Some(Duration::ZERO);
+        self.expires = Some(DateTime::UNIX_EPOCH);
+        self
+    }
+    /// Returns true if the name is a token and the value only has characters allowed in cookies
+    pub(crate) fn is_valid(&self) -> bool {
+        let value = self.value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(&self.value);
+        let attribute = |a: &Option<String>| a.as_ref().is_none_or(|a| !a.bytes().any(|b| b == b';' || b.is_ascii_control()));
+        !self.name.is_empty()
+            && self.name.bytes().all(is_tchar)
+            && value.bytes().all(is_cookie_octet)
+            && attribute(&self.path)
+            && attribute(&self.domain)
+    }
+}
+
+/// Writes the Set-Cookie header value
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", expires.format("%a, %d %b %Y %H:%M:%S GMT"))?;
+        }
+        // Browsers reject SameSite=None cookies that aren't secure
+        if self.secure || self.same_site == Some(SameSite::None) {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+/// `cookie-octet`, the characters allowed in a cookie value
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+/// The cookies sent with a request, see `Request::cookies`
+#[derive(Debug, Clone, Default)]
+pub struct CookieJar {
+    cookies: Vec<(String, String)>,
+}
+
+impl CookieJar {
+    /// Parses the Cookie headers. Malformed pairs are skipped.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let cookies = headers
+            .get_all("Cookie")
+            .flat_map(|header| header.split(';'))
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                let name = name.trim();
+                let value = value.trim();
+                let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                (!name.is_empty()).then(|| (name.to_owned(), value.to_owned()))
+            })
+            .collect();
+        CookieJar { cookies }
+    }
+    /// Returns the value of the first cookie with the name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.cookies.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+    /// Returns the value of a cookie set with `Cookie::signed`, `None` if it is missing or was changed
+    pub fn get_signed(&self, key: &Key, name: &str) -> Option<String> {
+        key.verify(name, self.get(name)?)
+    }
+    /// Returns the value of a cookie set with `Cookie::private`, `None` if it is missing or was changed
+    pub fn get_private(&self, key: &Key, name: &str) -> Option<String> {
+        key.decrypt(name, self.get(name)?)
+    }
+    /// Returns the names and values of the cookies, in the order they were sent
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.cookies.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+}
+
+/// Error for keys that can't be read or are too weak
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidKey(pub(crate) String);
+
+impl fmt::Display for InvalidKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid key: {}", self.0)
+    }
+}
+
+impl error::Error for InvalidKey {}
+
+/// The key signing and encrypting cookies, derived from a server secret
+#[derive(Clone)]
+pub struct Key {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl Key {
+    /// Derives the keys from a secret of at least 32 random bytes.
+    /// Fails if the secret is shorter.
+    pub fn from_secret(secret: &[u8]) -> Result<Self, InvalidKey> {
+        if secret.len() < 32 {
+            return Err(InvalidKey("cookie secrets must be at least 32 bytes".to_owned()));
+        }
+        Ok(Key {
+            signing: derive(secret, b"warv cookie signing"),
+            encryption: derive(secret, b"warv cookie encryption"),
+        })
+    }
+
+    fn mac(&self, name: &str, value: &str) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.signing).expect("HMAC takes keys of any size");
+        // The name is signed too, so a value can't be moved to another cookie
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    /// Returns `value.signature`
+    pub(crate) fn sign(&self, name: &str, value: &str) -> String {
+        let signature = self.mac(name, value).finalize().into_bytes();
+        format!("{}.{}", value, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    /// Returns the value of a signed value if the signature matches
+    pub(crate) fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        let (value, signature) = signed.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(name, value).verify_slice(&signature).ok()?;
+        Some(value.to_owned())
+    }
+
+    /// Returns the nonce and the encrypted value, authenticated with the name
+    pub(crate) fn encrypt(&self, name: &str, value: &str) -> String {
+        let cipher = Aes256Gcm::new(&self.encryption.into());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: name.as_bytes(),
+        };
+        let encrypted = cipher.encrypt(&nonce, payload).expect("AES-GCM encrypts values of any cookie size");
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&encrypted);
+        URL_SAFE_NO_PAD.encode(sealed)
+    }
+
+    /// Returns the decrypted value if it was encrypted for the name with this key
+    pub(crate) fn decrypt(&self, name: &str, sealed: &str) -> Option<String> {
+        let sealed = URL_SAFE_NO_PAD.decode(sealed).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, encrypted) = sealed.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().ok()?;
+        let cipher = Aes256Gcm::new(&self.encryption.into());
+        let payload = Payload {
+            msg: encrypted,
+            aad: name.as_bytes(),
+        };
+        let value = cipher.decrypt(&Nonce::from(nonce), payload).ok()?;
+        String::from_utf8(value).ok()
+    }
+}
+
+/// Hides the key material
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key { .. }")
+    }
+}
+
+/// Returns `len` random bytes from the operating system, base64url-encoded, e.g. for session ids
+pub(crate) fn random_token(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Derives a key for one use from the secret
+fn derive(secret: &[u8], label: &[u8]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> Key {
+        Key::from_secret(&[7; 32]).unwrap()
+    }
+
+    /// Changes the character at `at`
+    fn tamper(value: &str, at: usize) -> String {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes[at] = if bytes[at] == b'A' { b'B' } else { b'A' };
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn short_secret_is_rejected() {
+        assert!(Key::from_secret(&[7; 31]).is_err());
+        assert!(Key::from_secret(b"").is_err());
+    }
+
+    #[test]
+    fn signed_value_is_verified() {
+        let signed = key().sign("user", "ann");
+        assert!(signed.starts_with("ann."));
+        assert_eq!(key().verify("user", &signed).as_deref(), Some("ann"));
+    }
+
+    #[test]
+    fn changed_signed_value_is_rejected() {
+        let key = key();
+        let signed = key.sign("user", "ann");
+        let signature = signed.rsplit_once('.').unwrap().1;
+        assert_eq!(key.verify("user", &format!("bob.{}", signature)), None);
+        assert_eq!(key.verify("user", &tamper(&signed, signed.len() - 2)), None);
+        assert_eq!(key.verify("user", "ann"), None);
+        assert_eq!(key.verify("user", "ann."), None);
+        assert_eq!(key.verify("user", "ann.!!"), None);
+    }
+
+    #[test]
+    fn signed_value_is_bound_to_the_name() {
+        let key = key();
+        let signed = key.sign("role", "admin");
+        assert_eq!(key.verify("user", &signed), None);
+    }
+
+    #[test]
+    fn signed_value_is_bound_to_the_key() {
+        let signed = key().sign("user", "ann");
+        let other = Key::from_secret(&[8; 32]).unwrap();
+        assert_eq!(other.verify("user", &signed), None);
+    }
+
+    #[test]
+    fn private_value_is_decrypted() {
+        let key = key();
+        let sealed = key.encrypt("user", "ann");
+        assert!(!sealed.contains("ann"));
+        assert_eq!(key.decrypt("user", &sealed).as_deref(), Some("ann"));
+        // A nonce per value, equal values don't look equal
+        assert_ne!(key.encrypt("user", "ann"), sealed);
+    }
+
+    #[test]
+    fn changed_private_value_is_rejected() {
+        let key = key();
+        let sealed = key.encrypt("user", "ann");
+        assert_eq!(key.decrypt("user", &tamper(&sealed, sealed.len() - 2)), None);
+        assert_eq!(key.decrypt("user", &sealed[..sealed.len() - 4]), None);
+        // Shorter than a nonce
+        assert_eq!(key.decrypt("user", &sealed[..8]), None);
+        assert_eq!(key.decrypt("user", ""), None);
+        assert_eq!(key.decrypt("user", "!!"), None);
+    }
+
+    #[test]
+    fn private_value_is_bound_to_the_name_and_key() {
+        let sealed = key().encrypt("user", "ann");
+        assert_eq!(key().decrypt("admin", &sealed), None);
+        assert_eq!(Key::from_secret(&[8; 32]).unwrap().decrypt("user", &sealed), None);
+    }
+
+    #[test]
+    fn jar_reads_signed_and_private_cookies() {
+        let key = key();
+        let signed = Cookie::new("user", "ann").signed(&key);
+        let private = Cookie::new("cart", "3 items").private(&key);
+        let mut headers = HeaderMap::new();
+        headers
+            .try_append("Cookie", &format!("user={}; cart={}", signed.value(), private.value()))
+            .unwrap();
+        let jar = CookieJar::from_headers(&headers);
+        assert_eq!(jar.get_signed(&key, "user").as_deref(), Some("ann"));
+        assert_eq!(jar.get_private(&key, "cart").as_deref(), Some("3 items"));
+        // Each kind is only read the way it was written
+        assert_eq!(jar.get_private(&key, "user"), None);
+        assert_eq!(jar.get_signed(&key, "cart"), None);
+        assert_eq!(jar.get_signed(&key, "missing"), None);
+    }
+}
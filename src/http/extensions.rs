@@ -0,0 +1,93 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A value stored in extensions, cloned with the request or response holding it
+trait Extension: Any + Send + Sync {
+    fn clone_box(&self) -> Box<dyn Extension>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Any + Clone + Send + Sync> Extension for T {
+    fn clone_box(&self) -> Box<dyn Extension> {
+        Box::new(self.clone())
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Clone for Box<dyn Extension> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
+}
+
+/// Values attached to a request or response, one per type.
+/// Lets middlewares hand data such as the authenticated user or a request id
+/// to the handlers and middlewares after them.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    // An empty map doesn't allocate, so requests without extensions cost nothing
+    map: HashMap<TypeId, Box<dyn Extension>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Extensions::default()
+    }
+    /// Adds a value, returning the earlier value of the same type
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.into_any().downcast().ok().map(|old| *old))
+    }
+    /// Returns the value of the type
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.as_ref().as_any().downcast_ref())
+    }
+    /// Returns the value of the type for modification
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.as_mut().as_any_mut().downcast_mut())
+    }
+    /// Removes the value of the type, returning it
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.into_any().downcast().ok().map(|value| *value))
+    }
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+    /// Moves the values of `other` in, replacing values of the same type
+    pub(crate) fn extend(&mut self, other: Extensions) {
+        self.map.extend(other.map);
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.len()).finish()
+    }
+}
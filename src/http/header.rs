@@ -0,0 +1,175 @@
+use crate::http::method::is_tchar;
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Error for header names that are not tokens and values containing control characters
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvalidHeader {
+    Name(String),
+    Value(String),
+}
+
+impl fmt::Display for InvalidHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidHeader::Name(name) => write!(f, "Invalid header name {:?}", name),
+            InvalidHeader::Value(value) => write!(f, "Invalid header value {:?}", value),
+        }
+    }
+}
+
+impl error::Error for InvalidHeader {}
+
+/// A header field name.
+/// Keeps the case it was created with, but compares case-insensitively.
+#[derive(Debug, Clone, Eq)]
+pub struct HeaderName(String);
+
+impl HeaderName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for HeaderName {
+    type Err = InvalidHeader;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if name.is_empty() || !name.bytes().all(is_tchar) {
+            return Err(InvalidHeader::Name(name.to_owned()));
+        }
+        Ok(HeaderName(name.to_owned()))
+    }
+}
+
+impl PartialEq for HeaderName {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl PartialEq<str> for HeaderName {
+    fn eq(&self, other: &str) -> bool {
+        self.0.eq_ignore_ascii_case(other)
+    }
+}
+
+impl fmt::Display for HeaderName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A header field value.
+/// Rejects CR, LF and NUL so a value can never end the field it is sent in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderValue(String);
+
+impl HeaderValue {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for HeaderValue {
+    type Err = InvalidHeader;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0')) {
+            return Err(InvalidHeader::Value(value.to_owned()));
+        }
+        Ok(HeaderValue(value.trim_matches(|c| c == ' ' || c == '\t').to_owned()))
+    }
+}
+
+impl fmt::Display for HeaderValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Header fields of a request or response.
+/// Names are case-insensitive, a name can have several values,
+/// and fields are kept in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderMap {
+    fields: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        HeaderMap::default()
+    }
+    /// Returns the first value of the header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+    /// Returns every value of the header, in the order they were added
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.fields.iter().any(|(n, _)| n == name)
+    }
+    /// Sets the header, replacing all its previous values
+    pub fn insert(&mut self, name: HeaderName, value: HeaderValue) {
+        match self.fields.iter().position(|(n, _)| *n == name) {
+            Some(first) => {
+                self.fields[first] = (name, value);
+                let mut i = first + 1;
+                while i < self.fields.len() {
+                    if self.fields[i].0 == self.fields[first].0 {
+                        self.fields.remove(i);
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            None => self.fields.push((name, value)),
+        }
+    }
+    /// Adds a value to the header, keeping its previous values
+    pub fn append(&mut self, name: HeaderName, value: HeaderValue) {
+        self.fields.push((name, value));
+    }
+    /// Validates and sets the header, replacing all its previous values
+    pub fn try_insert(&mut self, name: &str, value: &str) -> Result<(), InvalidHeader> {
+        self.insert(name.parse()?, value.parse()?);
+        Ok(())
+    }
+    /// Validates and adds a value to the header, keeping its previous values
+    pub fn try_append(&mut self, name: &str, value: &str) -> Result<(), InvalidHeader> {
+        self.append(name.parse()?, value.parse()?);
+        Ok(())
+    }
+    /// Removes every value of the header, returning the first
+    pub fn remove(&mut self, name: &str) -> Option<HeaderValue> {
+        let mut removed = None;
+        self.fields.retain(|(n, v)| {
+            if n != name {
+                return true;
+            }
+            removed.get_or_insert_with(|| v.clone());
+            false
+        });
+        removed
+    }
+    /// Iterates over all fields in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+    /// Number of fields, counting every value of a name
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
@@ -0,0 +1,129 @@
+use crate::error::{Error, ResponseError};
+use crate::http::{HeaderMap, Response, StatusCode};
+
+/// Conversion of the values returned by handlers into a response
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+/// An empty response of the status
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Response {
+        Response::new(self)
+    }
+}
+
+/// A 200 OK plain text response
+impl IntoResponse for &str {
+    fn into_response(self) -> Response {
+        self.to_owned().into_response()
+    }
+}
+
+/// A 200 OK plain text response
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        let mut response = Response::ok();
+        response.insert_header("Content-Type".to_owned(), "text/plain; charset=utf-8".to_owned());
+        response.body(self.into_bytes());
+        response
+    }
+}
+
+/// A 200 OK binary response
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response {
+        let mut response = Response::ok();
+        response.insert_header("Content-Type".to_owned(), "application/octet-stream".to_owned());
+        response.body(self);
+        response
+    }
+}
+
+/// A 200 OK `application/json` response, a value that can't be serialized is an internal error
+#[cfg(feature = "json")]
+impl<T: serde::Serialize> IntoResponse for crate::extract::Json<T> {
+    fn into_response(self) -> Response {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => {
+                let mut response = Response::ok();
+                response.insert_header("Content-Type".to_owned(), "application/json".to_owned());
+                response.body(body);
+                response
+            }
+            Err(e) => Error::Internal(format!("Failed to serialize JSON: {}", e)).into_response(),
+        }
+    }
+}
+
+/// The response of the value with the status replaced
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Response {
+        let (status, value) = self;
+        let mut response = value.into_response();
+        response.set_status(status);
+        response
+    }
+}
+
+/// The response of the value with the status replaced and the headers set, replacing earlier values.
+/// Invalid headers are logged and dropped.
+impl<T: IntoResponse, const N: usize> IntoResponse for (StatusCode, [(&str, &str); N], T) {
+    fn into_response(self) -> Response {
+        let (status, headers, value) = self;
+        let mut response = value.into_response();
+        response.set_status(status);
+        for (name, value) in headers {
+            response.insert_header(name.to_owned(), value.to_owned());
+        }
+        response
+    }
+}
+
+/// The response of the value with the status replaced and the headers set, replacing earlier values
+impl<T: IntoResponse> IntoResponse for (StatusCode, HeaderMap, T) {
+    fn into_response(self) -> Response {
+        let (status, headers, value) = self;
+        let mut response = value.into_response();
+        response.set_status(status);
+        for name in headers.iter().map(|(n, _)| n).collect::<Vec<_>>() {
+            response.headers_mut().remove(name);
+        }
+        for (name, value) in headers.iter() {
+            response.append_header(name.to_owned(), value.to_owned());
+        }
+        response
+    }
+}
+
+/// The response of the value, or 404 Not Found for `None`
+impl<T: IntoResponse> IntoResponse for Option<T> {
+    fn into_response(self) -> Response {
+        match self {
+            Some(value) => value.into_response(),
+            None => Response::from_error(Error::NotFound(String::new())),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        Response::from_error(self)
+    }
+}
+
+/// Errors are rendered by the router, see `Router::set_error_renderer`
+impl<T: IntoResponse, E: ResponseError> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => Response::from_error(error),
+        }
+    }
+}
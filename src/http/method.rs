@@ -1,14 +1,20 @@
+use std::fmt;
 use std::str::FromStr;
 
 ///HTTP Methods
 #[derive(Eq, Hash, PartialEq, Clone, Debug)]
 pub enum Method {
     GET,
+    HEAD,
     POST,
     PUT,
+    PATCH,
     OPTIONS,
     DELETE,
     TRACE,
+    CONNECT,
+    /// Any other method token, e.g. the WebDAV `PROPFIND` or `MKCOL`.
+    Extension(String),
 }
 
 impl FromStr for Method {
@@ -17,25 +23,60 @@ impl FromStr for Method {
     fn from_str(input: &str) -> Result<Method, Self::Err> {
         match input {
             "GET" => Ok(Method::GET),
+            "HEAD" => Ok(Method::HEAD),
             "POST" => Ok(Method::POST),
             "PUT" => Ok(Method::PUT),
+            "PATCH" => Ok(Method::PATCH),
             "OPTIONS" => Ok(Method::OPTIONS),
             "DELETE" => Ok(Method::DELETE),
             "TRACE" => Ok(Method::TRACE),
-            _ => Err(()),
+            "CONNECT" => Ok(Method::CONNECT),
+            _ => {
+                if !input.is_empty() && input.bytes().all(is_tchar) {
+                    Ok(Method::Extension(input.to_owned()))
+                } else {
+                    Err(())
+                }
+            }
         }
     }
 }
 
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 impl Method {
     pub fn as_str(&self) -> &str {
         match self {
             Method::GET => "GET",
+            Method::HEAD => "HEAD",
             Method::POST => "POST",
             Method::PUT => "PUT",
+            Method::PATCH => "PATCH",
             Method::OPTIONS => "OPTIONS",
             Method::DELETE => "DELETE",
             Method::TRACE => "TRACE",
+            Method::CONNECT => "CONNECT",
+            Method::Extension(m) => m.as_str(),
         }
     }
+    /// Returns true for the methods RFC 9110 defines as safe (read-only).
+    pub fn is_safe(&self) -> bool {
+        matches!(
+            self,
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+        )
+    }
+    /// Returns true for the methods RFC 9110 defines as idempotent.
+    pub fn is_idempotent(&self) -> bool {
+        self.is_safe() || matches!(self, Method::PUT | Method::DELETE)
+    }
+}
+
+/// `tchar` from RFC 9110, the characters allowed in a token.
+pub(crate) fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
 }
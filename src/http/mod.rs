@@ -1,13 +1,29 @@
+mod accept;
+mod cookie;
+mod extensions;
+mod header;
+mod into_response;
 mod method;
+pub(crate) mod percent;
 mod request;
 mod response;
 mod statuscode;
-mod uri;
+pub(crate) mod uri;
 mod version;
 
+pub use accept::preferred_media_type;
+pub use cookie::{Cookie, CookieJar, InvalidKey, Key, SameSite};
+pub(crate) use cookie::random_token;
+pub use extensions::Extensions;
+pub use header::{HeaderMap, HeaderName, HeaderValue, InvalidHeader};
+pub use into_response::IntoResponse;
 pub use method::Method;
+pub use percent::{decode_form, encode_form};
+pub(crate) use method::is_tchar;
+#[cfg(feature = "json")]
+pub use request::JSON_LIMIT;
 pub use request::Request;
 pub use response::Response;
 pub use statuscode::StatusCode;
-pub use uri::Uri;
+pub use uri::{InvalidUri, Uri, UriForm};
 pub use version::Version;
@@ -0,0 +1,128 @@
+//! Percent-encoding and decoding helpers shared by the URI and routing code.
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+/// Returns true for the RFC 3986 unreserved characters, which never need encoding.
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+/// Percent-encodes everything except the unreserved characters,
+/// making the value safe to use as a path segment or query component.
+pub(crate) fn encode_component(input: &str) -> String {
+    encode(input, false)
+}
+
+/// Percent-encodes everything except the unreserved characters, and spaces as `+` if `plus` is set
+fn encode(input: &str, plus: bool) -> String {
+    let mut out = String::with_capacity(input.len());
+    for &b in input.as_bytes() {
+        if is_unreserved(b) {
+            out.push(b as char);
+        } else if plus && b == b' ' {
+            out.push('+');
+        } else {
+            out.push('%');
+            out.push(HEX[(b >> 4) as usize] as char);
+            out.push(HEX[(b & 0x0f) as usize] as char);
+        }
+    }
+    out
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decodes the percent-encoded bytes for which `keep` returns false.
+/// Returns `None` if the decoded bytes are not valid UTF-8.
+fn decode_except(input: &str, keep: impl Fn(u8) -> bool) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(h), Some(l)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                let b = (h << 4) | l;
+                if !keep(b) {
+                    out.push(b);
+                    i += 3;
+                    continue;
+                }
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Fully decodes a percent-encoded string.
+pub(crate) fn decode(input: &str) -> Option<String> {
+    decode_except(input, |_| false)
+}
+
+/// Decodes a request path for matching.
+/// `%2F` and `%25` stay encoded so a decoded path never gains segments and is never decoded twice,
+/// route parameters taken from it are decoded once more with `decode`.
+pub(crate) fn decode_path(input: &str) -> Option<String> {
+    decode_except(input, |b| b == b'/' || b == b'%')
+}
+
+/// Encodes the characters not allowed in a path, leaving `/` and existing percent escapes as they are.
+pub(crate) fn encode_path(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for &b in input.as_bytes() {
+        if is_unreserved(b) || b"/%!$&'()*+,;=:@".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push('%');
+            out.push(HEX[(b >> 4) as usize] as char);
+            out.push(HEX[(b & 0x0f) as usize] as char);
+        }
+    }
+    out
+}
+
+/// Decodes an `application/x-www-form-urlencoded` string, as used by query strings and forms,
+/// into its name and value pairs in order. `+` decodes to a space.
+/// Sequences that don't decode to UTF-8 are kept as received.
+pub fn decode_form(input: &str) -> Vec<(String, String)> {
+    let decode_part = |part: &str| {
+        let part = part.replace('+', " ");
+        decode(&part).unwrap_or(part)
+    };
+    input
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode_part(name), decode_part(value))
+        })
+        .collect()
+}
+
+/// Encodes name and value pairs as an `application/x-www-form-urlencoded` string,
+/// the inverse of `decode_form`. Spaces are encoded as `+`.
+pub fn encode_form<I, K, V>(pairs: I) -> String
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let mut out = String::new();
+    for (name, value) in pairs {
+        if !out.is_empty() {
+            out.push('&');
+        }
+        out.push_str(&encode(name.as_ref(), true));
+        out.push('=');
+        out.push_str(&encode(value.as_ref(), true));
+    }
+    out
+}
@@ -1,8 +1,22 @@
+use crate::error::Error;
+use crate::extract::from_pairs;
+use crate::http::decode_form;
+use crate::http::CookieJar;
+use crate::http::Extensions;
+use crate::http::HeaderMap;
 use crate::http::Method;
 
 use crate::http::Uri;
+use crate::http::UriForm;
 use crate::http::Version;
-use std::collections::HashMap;
+use crate::urls::{UrlError, UrlMap};
+use log::error;
+use serde::de::DeserializeOwned;
+use std::net::SocketAddr;
+
+/// Largest JSON body `Request::json` deserializes, 2 MB
+#[cfg(feature = "json")]
+pub const JSON_LIMIT: usize = 2 * 1024 * 1024;
 
 /// HTTP Request
 #[derive(Debug, Clone)]
@@ -14,14 +28,14 @@ impl Request {
     pub fn new(method: Method) -> Self {
         Request {
             parts: Parts {
-                method: method,
-                uri: Uri {
-                    path: "".to_owned(),
-                    query: None,
-                    pattern_params: None,
-                },
+                method,
+                uri: Uri::default(),
                 version: Version::HTTP1_1,
-                headers: HashMap::new(),
+                headers: HeaderMap::new(),
+                urls: UrlMap::new(),
+                server_name: None,
+                remote_addr: None,
+                extensions: Extensions::new(),
             },
             body: Vec::new(),
         }
@@ -33,22 +47,139 @@ impl Request {
 
     /// Returns the HTTP Verions
     pub fn version(&self) -> &Version{
-        return &self.parts.version;
+        &self.parts.version
+    }
+
+    /// Sets the HTTP Version
+    pub fn set_version(&mut self, version: Version) -> &Self {
+        self.parts.version = version;
+        self
     }
 
     /// Returns the headers
-    pub fn headers(&self) -> &HashMap<String, String> {
-        return &self.parts.headers;
+    pub fn headers(&self) -> &HeaderMap {
+        &self.parts.headers
+    }
+    /// Returns the headers for modification
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.parts.headers
+    }
+    /// Returns the cookies sent with the request
+    pub fn cookies(&self) -> CookieJar {
+        CookieJar::from_headers(&self.parts.headers)
+    }
+    /// Returns the value of a cookie sent with the request
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().get(name).map(str::to_owned)
+    }
+    /// Returns the host the request is for, without port and in lowercase.
+    /// Taken from an absolute-form target, the Host header, or the TLS server name (SNI) when the header is missing.
+    pub fn host(&self) -> Option<String> {
+        // The authority of an absolute-form target overrides the Host header (RFC 9112 section 3.2.2)
+        if self.parts.uri.form() == UriForm::Absolute {
+            if let Some(host) = self.parts.uri.host().filter(|h| !h.is_empty()) {
+                return Some(host.to_ascii_lowercase());
+            }
+        }
+        let header = self.parts.headers.get("Host").map(strip_port);
+        match header {
+            Some(host) if !host.is_empty() => Some(host.to_ascii_lowercase()),
+            _ => self.parts.server_name.as_ref().map(|s| s.to_ascii_lowercase()),
+        }
+    }
+    /// Returns the server name sent by the client during the TLS handshake (SNI)
+    pub fn server_name(&self) -> Option<&str> {
+        self.parts.server_name.as_deref()
+    }
+    /// Sets the TLS server name of the connection the request was received on
+    pub fn set_server_name(&mut self, name: Option<String>) -> &Self {
+        self.parts.server_name = name;
+        self
+    }
+    /// Returns the address of the client the request was received from
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.parts.remote_addr
+    }
+    /// Sets the address of the client the request was received from
+    pub fn set_remote_addr(&mut self, addr: Option<SocketAddr>) -> &Self {
+        self.parts.remote_addr = addr;
+        self
+    }
+    /// Returns the values attached to the request by middlewares, see `Extensions`
+    pub fn extensions(&self) -> &Extensions {
+        &self.parts.extensions
+    }
+    /// Returns the values attached to the request for modification
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.parts.extensions
+    }
+    /// Returns the media type of the body, in lowercase and without parameters
+    pub fn media_type(&self) -> Option<String> {
+        let content_type = self.parts.headers.get("Content-Type")?;
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        Some(media_type.to_ascii_lowercase())
+    }
+    /// Deserializes the `application/x-www-form-urlencoded` body into a struct or map.
+    /// Repeated fields deserialize into sequences.
+    /// Fails with 415 if the body isn't a form, 400 if it isn't UTF-8 and 422 if it doesn't match `T`.
+    pub fn form<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        if self.media_type().as_deref() != Some("application/x-www-form-urlencoded") {
+            return Err(Error::UnsupportedMediaType(
+                "Expected application/x-www-form-urlencoded".to_owned(),
+            ));
+        }
+        let body = std::str::from_utf8(&self.body)
+            .map_err(|_| Error::BadRequest("Form is not valid UTF-8".to_owned()))?;
+        from_pairs(decode_form(body)).map_err(|e| Error::UnprocessableEntity(format!("Invalid form: {}", e)))
+    }
+    /// Deserializes the JSON body, up to `JSON_LIMIT` bytes.
+    /// Fails with 415 if the body isn't `application/json` or `+json`, 413 if it is larger than the limit,
+    /// 400 if it isn't JSON and 422 if it doesn't match `T`.
+    #[cfg(feature = "json")]
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        self.json_with_limit(JSON_LIMIT)
+    }
+    /// Deserializes the JSON body like `json`, with a limit of `limit` bytes
+    #[cfg(feature = "json")]
+    pub fn json_with_limit<T: DeserializeOwned>(&self, limit: usize) -> Result<T, Error> {
+        let is_json = self
+            .media_type()
+            .is_some_and(|m| m == "application/json" || m.ends_with("+json"));
+        if !is_json {
+            return Err(Error::UnsupportedMediaType("Expected application/json".to_owned()));
+        }
+        if self.body.len() > limit {
+            return Err(Error::PayloadTooLarge(format!("JSON body larger than {} bytes", limit)));
+        }
+        serde_json::from_slice(&self.body).map_err(|e| {
+            // Well-formed JSON of the wrong shape is unprocessable, anything else is a bad request
+            if e.is_data() {
+                Error::UnprocessableEntity(format!("Invalid JSON: {}", e))
+            } else {
+                Error::BadRequest(format!("Malformed JSON: {}", e))
+            }
+        })
     }
     /// Sets the request body
     pub fn body(&mut self, body: Vec<u8>) -> &Self {
         self.body = body;
-        return self;
+        self
     }
-    /// Adds headers to the requests
+    /// Adds headers to the requests, replacing earlier values of the header.
+    /// Invalid names and values are logged and dropped.
     pub fn insert_header(&mut self, k: &str, v: &str) -> &Self {
-        self.parts.headers.insert(k.to_owned(), v.to_owned());
-        return self;
+        if let Err(e) = self.parts.headers.try_insert(k, v) {
+            error!("{}", e);
+        }
+        self
+    }
+    /// Adds a header value to the request, keeping earlier values of the header.
+    /// Invalid names and values are logged and dropped.
+    pub fn append_header(&mut self, k: &str, v: &str) -> &Self {
+        if let Err(e) = self.parts.headers.try_append(k, v) {
+            error!("{}", e);
+        }
+        self
     }
     /// Set the URI for the request.
     pub fn set_uri(&mut self, path: &str) -> &Self {
@@ -56,15 +187,34 @@ impl Request {
         self.parts.uri = uri;
         self
     }
+    /// Sets an already parsed URI for the request
+    pub(crate) fn set_parsed_uri(&mut self, uri: Uri) -> &Self {
+        self.parts.uri = uri;
+        self
+    }
     ///Returns the URI
     pub fn uri(&self) -> &Uri {
         &self.parts.uri
     }
-    /// Collects route parameters
-    pub fn add_params(&mut self, params: HashMap<String, String>) -> &Self {
-        self.parts.uri.pattern_params = Some(params.clone());
+    /// Collects route parameters, in the order of the route template
+    pub fn add_params(&mut self, params: Vec<(String, String)>) -> &Self {
+        self.parts.uri.pattern_params = Some(params);
+        self
+    }
+    /// Sets the labels captured from the host by a virtual host router
+    pub fn set_host_params(&mut self, params: Vec<(String, String)>) -> &Self {
+        self.parts.uri.host_params = Some(params);
+        self
+    }
+    /// Sets the named routes used by `url_for`
+    pub fn set_urls(&mut self, urls: UrlMap) -> &Self {
+        self.parts.urls = urls;
         self
     }
+    /// Generate the URL of a named route of the router handling this request.
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        self.parts.urls.url_for(name, params)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -72,5 +222,23 @@ struct Parts {
     method: Method,
     uri: Uri,
     version: Version,
-    headers: HashMap<String, String>,
+    headers: HeaderMap,
+    urls: UrlMap,
+    server_name: Option<String>,
+    remote_addr: Option<SocketAddr>,
+    extensions: Extensions,
+}
+
+/// Removes the port from a Host header value, keeping IPv6 literals intact
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+    match host.rfind(':') {
+        Some(i) => &host[..i],
+        None => host,
+    }
 }
@@ -1,7 +1,14 @@
+use crate::error::ResponseError;
+use crate::http::preferred_media_type;
+use crate::http::Cookie;
+use crate::http::Extensions;
+use crate::http::HeaderMap;
 use crate::http::StatusCode;
+use crate::routes::json_string;
 use crate::http::Version;
 use chrono::prelude::*;
-use std::collections::HashMap;
+use log::error;
+use std::sync::Arc;
 
 ///HTTP Response
 #[derive(Debug, Clone)]
@@ -14,9 +21,11 @@ impl Response {
     pub fn new(status: StatusCode) -> Self{
         Response {
             parts: Parts {
-                status: status,
+                status,
                 version: Version::HTTP1_1,
-                headers: HashMap::new(),
+                headers: HeaderMap::new(),
+                error: None,
+                extensions: Extensions::new(),
             },
             body: Vec::new(),
         }
@@ -28,7 +37,9 @@ impl Response {
             parts: Parts {
                 status: StatusCode::OK,
                 version: Version::HTTP1_1,
-                headers: HashMap::new(),
+                headers: HeaderMap::new(),
+                error: None,
+                extensions: Extensions::new(),
             },
             body: Vec::new(),
         }
@@ -39,7 +50,9 @@ impl Response {
             parts: Parts {
                 status: StatusCode::BadRequest,
                 version: Version::HTTP1_1,
-                headers: HashMap::new(),
+                headers: HeaderMap::new(),
+                error: None,
+                extensions: Extensions::new(),
             },
             body: Vec::new(),
         }
@@ -50,7 +63,9 @@ impl Response {
             parts: Parts {
                 status: StatusCode::NoContent,
                 version: Version::HTTP1_1,
-                headers: HashMap::new(),
+                headers: HeaderMap::new(),
+                error: None,
+                extensions: Extensions::new(),
             },
             body: Vec::new(),
         }
@@ -61,7 +76,9 @@ impl Response {
             parts: Parts {
                 status: StatusCode::NotFound,
                 version: Version::HTTP1_1,
-                headers: HashMap::new(),
+                headers: HeaderMap::new(),
+                error: None,
+                extensions: Extensions::new(),
             },
             body: b"Not Found".to_vec(),
         }
@@ -72,31 +89,163 @@ impl Response {
             parts: Parts {
                 status: StatusCode::InternalServerError,
                 version: Version::HTTP1_1,
-                headers: HashMap::new(),
+                headers: HeaderMap::new(),
+                error: None,
+                extensions: Extensions::new(),
             },
             body: Vec::new(),
         }
     }
+    /// Builds a page describing the status.
+    /// Rendered as JSON, HTML or plain text, whichever the Accept header of the request prefers.
+    pub fn error_page(status: StatusCode, accept: Option<&str>) -> Self {
+        Response::error_page_with_message(status, None, accept)
+    }
+    /// Builds a page describing the status with a message explaining the error, see `error_page`.
+    pub fn error_page_with_message(status: StatusCode, message: Option<&str>, accept: Option<&str>) -> Self {
+        let code = status.as_u16();
+        let reason = status.reason().trim().to_owned();
+        // A message repeating the reason phrase adds nothing
+        let message = message.filter(|m| !m.is_empty() && *m != reason);
+        let offers = ["text/plain", "text/html", "application/json"];
+        let (content_type, body) = match preferred_media_type(accept, &offers) {
+            Some("application/json") => (
+                "application/json",
+                match message {
+                    Some(m) => format!(
+                        "{{\"status\":{},\"error\":{},\"message\":{}}}",
+                        code,
+                        json_string(&reason),
+                        json_string(m)
+                    ),
+                    None => format!("{{\"status\":{},\"error\":{}}}", code, json_string(&reason)),
+                },
+            ),
+            Some("text/html") => (
+                "text/html; charset=utf-8",
+                format!(
+                    "<!DOCTYPE html>\n<html><head><title>{0} {1}</title></head><body><h1>{0} {1}</h1>{2}</body></html>\n",
+                    code,
+                    reason,
+                    message.map_or(String::new(), |m| format!("<p>{}</p>", escape_html(m)))
+                ),
+            ),
+            _ => (
+                "text/plain; charset=utf-8",
+                match message {
+                    Some(m) => format!("{}: {}", reason, m),
+                    None => reason,
+                },
+            ),
+        };
+        let mut response = Response::new(status);
+        response.insert_header("Content-Type".to_owned(), content_type.to_owned());
+        response.body(body.into_bytes());
+        response
+    }
+    /// Builds the response of an error, keeping the error available through `error`
+    pub fn from_error<E: ResponseError>(error: E) -> Self {
+        let mut response = error.error_response(None);
+        response.parts.error = Some(Arc::new(error));
+        response
+    }
+    /// Returns the error the response was built from, if any.
+    /// Lets middlewares see why a handler failed.
+    pub fn error(&self) -> Option<&dyn ResponseError> {
+        self.parts.error.as_deref()
+    }
+    /// Returns the shared error, to render it
+    pub(crate) fn shared_error(&self) -> Option<Arc<dyn ResponseError>> {
+        self.parts.error.clone()
+    }
+    /// Takes the status, body and headers of `rendered`, such as the page rendering an error,
+    /// keeping the other headers and the extensions of this response.
+    /// The Content-Type of this response described the replaced body and is dropped.
+    pub(crate) fn replace_content(&mut self, rendered: Response) {
+        self.parts.status = rendered.parts.status;
+        self.body = rendered.body;
+        self.parts.headers.remove("Content-Type");
+        for (name, _) in rendered.parts.headers.iter() {
+            self.parts.headers.remove(name);
+        }
+        for (name, value) in rendered.parts.headers.iter() {
+            // Valid already, the names and values were checked when added to `rendered`
+            let _ = self.parts.headers.try_append(name, value);
+        }
+        self.parts.extensions.extend(rendered.parts.extensions);
+    }
+    /// Returns the values attached to the response, e.g. by a handler for the middlewares wrapping it
+    pub fn extensions(&self) -> &Extensions {
+        &self.parts.extensions
+    }
+    /// Returns the values attached to the response for modification
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.parts.extensions
+    }
     /// Returns the status
     pub fn status(&self) -> &StatusCode {
-        return &self.parts.status;
+        &self.parts.status
+    }
+    /// Sets the status
+    pub fn set_status(&mut self, status: StatusCode) -> &Self {
+        self.parts.status = status;
+        self
     }
     // Sets the Body
     pub fn body(&mut self, body: Vec<u8>) -> &Self {
         self.body = body;
-        return self;
+        self
     }
     /// Returns HTTP Version
     pub fn version(&self) -> &Version {
         &self.parts.version
     }
-    /// Add headers
+    /// Returns the headers
+    pub fn headers(&self) -> &HeaderMap {
+        &self.parts.headers
+    }
+    /// Returns the headers for modification
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.parts.headers
+    }
+    /// Add headers, replacing earlier values of the header.
+    /// Invalid names and values, such as values containing line breaks, are logged and dropped.
     pub fn insert_header(&mut self, k: String, v: String) -> &Self {
-        self.parts.headers.insert(k, v);
-        return self;
+        if let Err(e) = self.parts.headers.try_insert(&k, &v) {
+            error!("{}", e);
+        }
+        self
+    }
+    /// Add a header value, keeping earlier values of the header, e.g. for several `Set-Cookie` headers.
+    /// Invalid names and values are logged and dropped.
+    pub fn append_header(&mut self, k: String, v: String) -> &Self {
+        if let Err(e) = self.parts.headers.try_append(&k, &v) {
+            error!("{}", e);
+        }
+        self
+    }
+    /// Adds a Set-Cookie header for the cookie.
+    /// Cookies with invalid names or values, such as values containing `;`, are logged and dropped.
+    pub fn add_cookie(&mut self, cookie: &Cookie) -> &Self {
+        if !cookie.is_valid() {
+            error!("Invalid cookie {:?}", cookie.name());
+            return self;
+        }
+        self.append_header("Set-Cookie".to_owned(), cookie.to_string())
+    }
+    /// Removes the cookie set for the path `/` from the client, see `Cookie::removal` for other paths
+    pub fn remove_cookie(&mut self, name: &str) -> &Self {
+        self.add_cookie(&Cookie::new(name, "").path("/").removal())
     }
     /// Formats the response to be sent
     pub fn format(&self) -> Vec<u8> {
+        let mut response_bytes = self.format_head();
+        response_bytes.extend_from_slice(&self.body);
+        response_bytes
+    }
+    /// Formats the status line and headers only, as sent in reply to a HEAD request.
+    /// The Content-Length still reflects the body that would have been sent.
+    pub fn format_head(&self) -> Vec<u8> {
         let dt = Utc::now();
         let mut response_str = format!(
             "{} {} {}\r\nContent-Length: {}\r\nServer: warv\r\nDate: {} \r\n",
@@ -106,13 +255,12 @@ impl Response {
             self.body.len(),
             dt.to_rfc2822(),
         );
-        for (k, v) in self.parts.headers.iter() {
+        // Content-Length is always computed from the body
+        for (k, v) in self.parts.headers.iter().filter(|(k, _)| !k.eq_ignore_ascii_case("Content-Length")) {
             response_str = format!("{}{}: {}\r\n", response_str, k, v);
         }
         response_str = format!("{}\r\n", response_str);
-        let mut response_bytes = response_str.into_bytes();
-        response_bytes.extend_from_slice(&self.body);
-        response_bytes
+        response_str.into_bytes()
     }
 }
 
@@ -120,6 +268,23 @@ impl Response {
 struct Parts {
     status: StatusCode,
     version: Version,
-    headers: HashMap<String, String>,
-    // pub extensions: HashMap<String, String>,
+    headers: HeaderMap,
+    error: Option<Arc<dyn ResponseError>>,
+    extensions: Extensions,
+}
+
+/// Escapes the characters with a meaning in HTML
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
 }
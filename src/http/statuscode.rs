@@ -40,6 +40,8 @@ pub enum StatusCode {
     RequestRangeNotSatisfiable,
     ExpectationFailed,
     Teapot,
+    MisdirectedRequest,
+    UnprocessableEntity,
     UpgradeRequired,
     PreconditionRequired,
     TooManyRequests,
@@ -100,6 +102,8 @@ impl StatusCode {
             StatusCode::RequestRangeNotSatisfiable => 416,
             StatusCode::ExpectationFailed => 417,
             StatusCode::Teapot => 418,
+            StatusCode::MisdirectedRequest => 421,
+            StatusCode::UnprocessableEntity => 422,
             StatusCode::UpgradeRequired => 426,
             StatusCode::PreconditionRequired => 428,
             StatusCode::TooManyRequests => 429,
@@ -160,6 +164,8 @@ impl StatusCode {
             StatusCode::RequestRangeNotSatisfiable => "Requested Range Not Satisfiable",
             StatusCode::ExpectationFailed => "Expectation Failed",
             StatusCode::Teapot => "I'm a teapot ",
+            StatusCode::MisdirectedRequest => "Misdirected Request",
+            StatusCode::UnprocessableEntity => "Unprocessable Entity",
             StatusCode::UpgradeRequired => "Upgrade Required",
             StatusCode::PreconditionRequired => "Precondition Required",
             StatusCode::TooManyRequests => "Too Many Requests",
@@ -1,52 +1,369 @@
-use std::collections::HashMap;
+use crate::http::percent::decode_form;
+use std::error;
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+
+/// The form of a request target (RFC 9112 section 3.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UriForm {
+    /// A path with an optional query, `/index.html?page=2`
+    #[default]
+    Origin,
+    /// An absolute URI, `http://example.com/index.html`, sent to proxies
+    Absolute,
+    /// Only an authority, `example.com:443`, used by CONNECT
+    Authority,
+    /// `*`, used by server-wide OPTIONS requests
+    Asterisk,
+}
+
+/// Error for URIs containing characters not allowed where they appear, or not in one of the request target forms
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidUri {
+    uri: String,
+    reason: &'static str,
+}
+
+impl fmt::Display for InvalidUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid URI {:?}: {}", self.uri, self.reason)
+    }
+}
+
+impl error::Error for InvalidUri {}
 
 /// URI Struct
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Uri {
     pub path: String,
-    pub query: Option<HashMap<String, String>>,
-    pub pattern_params: Option<HashMap<String, String>>,
+    /// The decoded query parameters, in the order they were sent
+    pub query: Option<Vec<(String, String)>>,
+    /// The query string as received, without the leading `?`
+    pub raw_query: Option<String>,
+    /// The decoded path parameters of the matched route, in the order of the route template
+    pub pattern_params: Option<Vec<(String, String)>>,
+    /// The labels captured from the host by a virtual host router, see `Router::add_host`
+    pub host_params: Option<Vec<(String, String)>>,
+    form: UriForm,
+    scheme: Option<String>,
+    authority: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+    fragment: Option<String>,
 }
 impl Uri {
+    /// Parses the URI, keeping text that isn't a valid URI as the path, up to the first `?`.
+    /// Use `Uri::parse` to reject invalid URIs.
     pub fn new(path: &str) -> Self {
-        let mut query_values = path.split("?");
-        let p = query_values.next();
-        let qv = match query_values.next() {
-            Some(q) => Some(
-                q.split('&')
-                    .filter_map(|pair| {
-                        let mut split = pair.splitn(2, '=');
-                        Some((
-                            split.next()?.to_string(),
-                            split.next().unwrap_or("").to_string(),
-                        ))
-                    })
-                    .collect::<HashMap<String, String>>(),
-            ),
-            None => None,
-        };
-        return Uri {
-            path: p.unwrap().to_owned(),
-            query: qv,
-            pattern_params: None,
-        };
-    }
-    /// Returns the path
+        match Uri::parse(path) {
+            Ok(uri) => uri,
+            Err(_) => {
+                let (p, raw_query) = match path.split_once('?') {
+                    Some((p, query)) => (p, Some(query)),
+                    None => (path, None),
+                };
+                Uri {
+                    path: p.to_owned(),
+                    ..Uri::default()
+                }
+                .with_query(raw_query)
+            }
+        }
+    }
+
+    /// Parses a URI in origin-form (`/path?query`), absolute-form (`scheme://authority/path?query`)
+    /// or asterisk-form (`*`), with an optional fragment (RFC 3986 section 3).
+    /// Authority-form is ambiguous with absolute URIs and parsed by `Uri::parse_authority`.
+    /// The query is only required to be visible ASCII, like browsers send it.
+    pub fn parse(uri: &str) -> Result<Uri, InvalidUri> {
+        let invalid = |reason| InvalidUri {
+            uri: uri.to_owned(),
+            reason,
+        };
+        if uri == "*" {
+            return Ok(Uri {
+                path: "*".to_owned(),
+                form: UriForm::Asterisk,
+                ..Uri::default()
+            });
+        }
+
+        let (rest, fragment) = match uri.split_once('#') {
+            Some((rest, fragment)) => (rest, Some(fragment)),
+            None => (uri, None),
+        };
+        if !fragment.is_none_or(|f| f.bytes().all(is_query_char)) || !valid_escapes(fragment.unwrap_or("")) {
+            return Err(invalid("invalid character in fragment"));
+        }
+        // Only the first `?` starts the query, later ones are part of it
+        let (rest, raw_query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query)),
+            None => (rest, None),
+        };
+        // Browsers send characters such as `[]{}|^` and stray `%` unencoded in queries (WHATWG URL),
+        // so any visible ASCII is accepted
+        if !raw_query.is_none_or(|q| q.bytes().all(|b| b.is_ascii_graphic())) {
+            return Err(invalid("invalid character in query"));
+        }
+
+        let mut parsed = Uri {
+            fragment: fragment.map(str::to_owned),
+            ..Uri::default()
+        };
+        // A request target starting with `//` is a path with an empty segment, not an authority
+        let path = if rest.starts_with('/') {
+            rest
+        } else {
+            let (scheme, hier) = rest.split_once(':').ok_or_else(|| invalid("not an absolute URI or path"))?;
+            if !is_scheme(scheme) {
+                return Err(invalid("invalid scheme"));
+            }
+            let scheme = scheme.to_ascii_lowercase();
+            parsed.form = UriForm::Absolute;
+            let path = match hier.strip_prefix("//") {
+                Some(hier) => {
+                    let end = hier.find('/').unwrap_or(hier.len());
+                    parsed.set_authority(&hier[..end]).map_err(invalid)?;
+                    // HTTP URIs always name a host (RFC 9110 section 4.2)
+                    if parsed.host.as_deref() == Some("") && matches!(scheme.as_str(), "http" | "https") {
+                        return Err(invalid("missing host"));
+                    }
+                    &hier[end..]
+                }
+                None => hier,
+            };
+            parsed.scheme = Some(scheme);
+            path
+        };
+        if !path.bytes().all(|b| b == b'/' || is_pchar(b)) || !valid_escapes(path) {
+            return Err(invalid("invalid character in path"));
+        }
+        parsed.path = path.to_owned();
+        Ok(parsed.with_query(raw_query))
+    }
+
+    /// Parses a URI in authority-form, `host:port`, as sent with CONNECT (RFC 9112 section 3.2.3)
+    pub fn parse_authority(authority: &str) -> Result<Uri, InvalidUri> {
+        let invalid = |reason| InvalidUri {
+            uri: authority.to_owned(),
+            reason,
+        };
+        let mut parsed = Uri {
+            form: UriForm::Authority,
+            ..Uri::default()
+        };
+        parsed.set_authority(authority).map_err(invalid)?;
+        if authority.contains('@') {
+            return Err(invalid("user information not allowed"));
+        }
+        if parsed.host.as_deref() == Some("") || parsed.port.is_none() {
+            return Err(invalid("expected host and port"));
+        }
+        Ok(parsed)
+    }
+
+    /// Parses and sets the authority, `[userinfo@]host[:port]`
+    fn set_authority(&mut self, authority: &str) -> Result<(), &'static str> {
+        let host_port = match authority.rsplit_once('@') {
+            Some((userinfo, host_port)) => {
+                if !userinfo.bytes().all(|b| b == b':' || is_unreserved_or_sub_delim(b)) || !valid_escapes(userinfo) {
+                    return Err("invalid character in user information");
+                }
+                host_port
+            }
+            None => authority,
+        };
+        // The port follows the last colon outside an IP literal
+        let (host, port) = match host_port.rfind(':') {
+            Some(i) if !host_port[i..].contains(']') => (&host_port[..i], Some(&host_port[i + 1..])),
+            _ => (host_port, None),
+        };
+        if let Some(literal) = host.strip_prefix('[') {
+            let literal = literal.strip_suffix(']').ok_or("unterminated IP literal")?;
+            let future = literal.starts_with(['v', 'V']) && literal.contains('.');
+            if literal.parse::<Ipv6Addr>().is_err() && !future {
+                return Err("invalid IP literal");
+            }
+        } else if !host.bytes().all(is_unreserved_or_sub_delim) || !valid_escapes(host) {
+            return Err("invalid character in host");
+        }
+        self.port = match port {
+            // An empty port is the same as none
+            None | Some("") => None,
+            Some(port) if port.bytes().all(|b| b.is_ascii_digit()) => {
+                Some(port.parse().map_err(|_| "port out of range")?)
+            }
+            Some(_) => return Err("invalid port"),
+        };
+        self.host = Some(host.to_owned());
+        self.authority = Some(authority.to_owned());
+        Ok(())
+    }
+
+    fn with_query(mut self, raw_query: Option<&str>) -> Self {
+        self.query = raw_query.map(decode_form);
+        self.raw_query = raw_query.map(str::to_owned);
+        self
+    }
+
+    /// Returns the form of the URI
+    pub fn form(&self) -> UriForm {
+        self.form
+    }
+    /// Returns the scheme in lowercase, for absolute URIs
+    pub fn scheme(&self) -> Option<&str> {
+        self.scheme.as_deref()
+    }
+    /// Returns the authority as received, `[userinfo@]host[:port]`
+    pub fn authority(&self) -> Option<&str> {
+        self.authority.as_deref()
+    }
+    /// Returns the host as received, IP literals in brackets
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+    /// Returns the port, if the authority has one
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+    /// Returns the fragment, without the leading `#`
+    pub fn fragment(&self) -> Option<&str> {
+        self.fragment.as_deref()
+    }
+    /// Returns the path, `/` for a URI with an authority and an empty path (RFC 9112 section 3.2.1)
     pub fn path(&self) -> &str {
+        if self.path.is_empty() && self.authority.is_some() && self.form == UriForm::Absolute {
+            return "/";
+        }
         self.path.as_str()
     }
-    /// Returns the query parameters
+    /// Returns the first value of a query parameter
     pub fn query(&self, id: &str) -> Option<String> {
-        if self.query.is_some() {
-            return self.query.as_ref()?.get(id).cloned();
-        }
-        return None;
+        self.query_pairs()
+            .iter()
+            .find(|(k, _)| k == id)
+            .map(|(_, v)| v.clone())
+    }
+    /// Returns all values of a query parameter, in order
+    pub fn query_all(&self, id: &str) -> Vec<String> {
+        self.query_pairs()
+            .iter()
+            .filter(|(k, _)| k == id)
+            .map(|(_, v)| v.clone())
+            .collect()
+    }
+    /// Returns the decoded query parameters, in order
+    pub fn query_pairs(&self) -> &[(String, String)] {
+        self.query.as_deref().unwrap_or(&[])
     }
-    /// Returns the path parameters
+    /// Returns the query string as received, without the leading `?`
+    pub fn raw_query(&self) -> Option<&str> {
+        self.raw_query.as_deref()
+    }
+    /// Returns the path parameter, or else the host label captured with the name
     pub fn param(&self, id: &str) -> Option<String> {
-        if self.pattern_params.is_some() {
-            return self.pattern_params.as_ref()?.get(id).cloned();
+        self.pattern_params
+            .iter()
+            .chain(&self.host_params)
+            .flatten()
+            .find(|(name, _)| name == id)
+            .map(|(_, value)| value.clone())
+    }
+    /// Returns the host label captured with the name
+    pub fn host_param(&self, id: &str) -> Option<&str> {
+        self.host_params
+            .iter()
+            .flatten()
+            .find(|(name, _)| name == id)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl FromStr for Uri {
+    type Err = InvalidUri;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        Uri::parse(uri)
+    }
+}
+
+/// Writes the URI as parsed, so a valid URI round-trips through `Uri::parse`
+impl fmt::Display for Uri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(scheme) = &self.scheme {
+            write!(f, "{}:", scheme)?;
+            if let Some(authority) = &self.authority {
+                write!(f, "//{}", authority)?;
+            }
+        } else if let Some(authority) = &self.authority {
+            // Authority-form has nothing else
+            return f.write_str(authority);
         }
-        return None;
+        f.write_str(&self.path)?;
+        if let Some(query) = &self.raw_query {
+            write!(f, "?{}", query)?;
+        }
+        if let Some(fragment) = &self.fragment {
+            write!(f, "#{}", fragment)?;
+        }
+        Ok(())
+    }
+}
+
+/// `scheme = ALPHA *( ALPHA / DIGIT / "+" / "-" / "." )`
+fn is_scheme(scheme: &str) -> bool {
+    scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'-' | b'.'))
+}
+
+/// Unreserved characters, sub-delims and `%` of escapes
+fn is_unreserved_or_sub_delim(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"-._~%!$&'()*+,;=".contains(&b)
+}
+
+/// Characters allowed in path segments
+fn is_pchar(b: u8) -> bool {
+    is_unreserved_or_sub_delim(b) || b == b':' || b == b'@'
+}
+
+/// Characters allowed in the query and fragment
+fn is_query_char(b: u8) -> bool {
+    is_pchar(b) || b == b'/' || b == b'?'
+}
+
+/// Returns true if every `%` starts an escape of two hex digits
+fn valid_escapes(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    bytes.iter().enumerate().filter(|(_, &b)| b == b'%').all(|(i, _)| {
+        bytes.get(i + 1).is_some_and(u8::is_ascii_hexdigit) && bytes.get(i + 2).is_some_and(u8::is_ascii_hexdigit)
+    })
+}
+
+/// Normalizes a path by removing `.` and `..` segments (RFC 3986 section 5.2.4)
+/// and collapsing repeated slashes. The result always starts with `/` and never leaves the root.
+pub(crate) fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/') {
+        trailing_slash = false;
+        match segment {
+            "" => trailing_slash = true,
+            "." => trailing_slash = true,
+            ".." => {
+                segments.pop();
+                trailing_slash = true;
+            }
+            s => segments.push(s),
+        }
+    }
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
     }
+    normalized
 }
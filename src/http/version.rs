@@ -1,8 +1,9 @@
 use std::str::FromStr;
 
 ///HTTP Version
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Version {
+    HTTP1_0,
     HTTP1_1,
 }
 
@@ -11,6 +12,7 @@ impl FromStr for Version {
 
     fn from_str(input: &str) -> Result<Version, Self::Err> {
         match input {
+            "HTTP/1.0" => Ok(Version::HTTP1_0),
             "HTTP/1.1" => Ok(Version::HTTP1_1),
             _ => Err(()),
         }
@@ -19,6 +21,7 @@ impl FromStr for Version {
 impl Version {
     pub fn as_str(&self) -> &str {
         match self {
+            Version::HTTP1_0 => "HTTP/1.0",
             Version::HTTP1_1 => "HTTP/1.1",
         }
     }
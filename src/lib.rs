@@ -1,11 +1,15 @@
 #[macro_use]
 extern crate may;
 mod middlewarewrapper;
-mod error;
+mod parser;
+pub mod error;
+pub mod extract;
 pub mod handler;
 pub mod middleware;
 pub mod http;
 pub mod router;
+pub mod routes;
 pub mod server;
 pub mod state;
+pub mod urls;
 pub mod middlewares;
\ No newline at end of file
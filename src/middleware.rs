@@ -10,4 +10,11 @@ pub trait Middleware: Send + Sync {
         state: State,
         next: &dyn Handler,
     ) -> Response;
+    /// Name of the middleware, used when listing routes.
+    /// Defaults to the type name without its module path or generic arguments.
+    fn name(&self) -> &'static str {
+        let name = std::any::type_name::<Self>();
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name)
+    }
 }
@@ -0,0 +1,252 @@
+//! HTTP authentication (RFC 9110 section 11) with the Basic (RFC 7617) and Bearer (RFC 6750) schemes.
+//!
+//! The middlewares pass the credentials to a callback, which returns the identity they belong to.
+//! The identity is attached to the request extensions for handlers to take as `Extension<T>`,
+//! requests without valid credentials are answered with 401 Unauthorized and a challenge.
+
+use crate::error::{Error, ResponseError};
+use crate::handler::Handler;
+use crate::http::{Request, Response};
+use crate::middleware::Middleware;
+use crate::state::State;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+/// Returns the identity of a user name and password
+type Verify<T> = dyn Fn(&str, &str) -> Option<T> + Send + Sync;
+/// Returns the identity a token belongs to
+type Validate<T> = dyn Fn(&str) -> Option<T> + Send + Sync;
+
+/// Checks user names and passwords sent with the Basic scheme
+pub struct BasicAuthMiddleware<T> {
+    realm: String,
+    verify: Box<Verify<T>>,
+}
+
+impl<T: Clone + Send + Sync + 'static> BasicAuthMiddleware<T> {
+    /// `verify` returns the identity of a user name and password, `None` if they are not valid.
+    /// Passwords should be compared in constant time.
+    pub fn new<F>(realm: impl Into<String>, verify: F) -> Self
+    where
+        F: Fn(&str, &str) -> Option<T> + Send + Sync + 'static,
+    {
+        BasicAuthMiddleware {
+            realm: realm.into(),
+            verify: Box::new(verify),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Middleware for BasicAuthMiddleware<T> {
+    fn handle(&self, mut req: Request, state: State, next: &dyn Handler) -> Response {
+        let identity = credentials(&req, "Basic")
+            .and_then(|encoded| STANDARD.decode(encoded).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                // The user name can't contain a colon, the password can
+                let (user, password) = decoded.split_once(':')?;
+                (self.verify)(user, password)
+            });
+        match identity {
+            Some(identity) => {
+                req.extensions_mut().insert(identity);
+                next.handle(req, state)
+            }
+            None => {
+                let challenge = format!("Basic realm={}, charset=\"UTF-8\"", quote(&self.realm));
+                unauthorized(&req, challenge, "Invalid or missing credentials")
+            }
+        }
+    }
+}
+
+/// Checks tokens sent with the Bearer scheme
+pub struct BearerAuthMiddleware<T> {
+    realm: String,
+    validate: Box<Validate<T>>,
+}
+
+impl<T: Clone + Send + Sync + 'static> BearerAuthMiddleware<T> {
+    /// `validate` returns the identity a token belongs to, `None` if it is not valid
+    pub fn new<F>(realm: impl Into<String>, validate: F) -> Self
+    where
+        F: Fn(&str) -> Option<T> + Send + Sync + 'static,
+    {
+        BearerAuthMiddleware {
+            realm: realm.into(),
+            validate: Box::new(validate),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Middleware for BearerAuthMiddleware<T> {
+    fn handle(&self, mut req: Request, state: State, next: &dyn Handler) -> Response {
+        let Some(token) = credentials(&req, "Bearer") else {
+            return unauthorized(&req, bearer_challenge(&self.realm, None), "Missing token");
+        };
+        match (self.validate)(token) {
+            Some(identity) => {
+                req.extensions_mut().insert(identity);
+                next.handle(req, state)
+            }
+            None => unauthorized(&req, bearer_challenge(&self.realm, Some("Invalid token")), "Invalid token"),
+        }
+    }
+}
+
+/// Returns the credentials sent with the scheme in the Authorization header
+pub(crate) fn credentials<'a>(req: &'a Request, scheme: &str) -> Option<&'a str> {
+    let (sent, credentials) = req.headers().get("Authorization")?.split_once(' ')?;
+    let credentials = credentials.trim();
+    (sent.eq_ignore_ascii_case(scheme) && !credentials.is_empty()).then_some(credentials)
+}
+
+/// The challenge of the Bearer scheme, with an `invalid_token` error if a token was sent
+pub(crate) fn bearer_challenge(realm: &str, error: Option<&str>) -> String {
+    match error {
+        Some(description) => {
+            // Descriptions are limited to visible ASCII without quotes and backslashes (RFC 6750 section 3)
+            let description: String = description
+                .chars()
+                .filter(|&c| c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\')
+                .collect();
+            format!(
+                "Bearer realm={}, error=\"invalid_token\", error_description=\"{}\"",
+                quote(realm),
+                description
+            )
+        }
+        None => format!("Bearer realm={}", quote(realm)),
+    }
+}
+
+/// A 401 Unauthorized response with the challenge, rendered like other errors
+pub(crate) fn unauthorized(req: &Request, challenge: String, message: &str) -> Response {
+    let error = Error::Unauthorized(message.to_owned());
+    let mut response = error.error_response(req.headers().get("Accept"));
+    response.insert_header("WWW-Authenticate".to_owned(), challenge);
+    response
+}
+
+/// Quotes a challenge parameter
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::HandlerType;
+    use crate::http::Method;
+
+    /// Sends a request with the Authorization header through the middleware.
+    /// Returns the status, the challenge and the identity the handler got.
+    fn send(middleware: &dyn Middleware, authorization: Option<&str>) -> (u16, Option<String>, Option<String>) {
+        let mut req = Request::new(Method::GET);
+        if let Some(authorization) = authorization {
+            req.headers_mut().try_insert("Authorization", authorization).unwrap();
+        }
+        let handler = HandlerType::Stateless(Box::new(|req: Request| {
+            let mut response = Response::ok();
+            if let Some(identity) = req.extensions().get::<String>() {
+                response.insert_header("X-Identity".to_owned(), identity.clone());
+            }
+            response
+        }));
+        let response = middleware.handle(req, State::new(), &handler);
+        let header = |name: &str| response.headers().get(name).map(str::to_owned);
+        (response.status().as_u16(), header("WWW-Authenticate"), header("X-Identity"))
+    }
+
+    fn basic() -> BasicAuthMiddleware<String> {
+        BasicAuthMiddleware::new("api", |user: &str, password: &str| {
+            (user == "ann" && password == "p:ss").then(|| user.to_owned())
+        })
+    }
+
+    fn bearer() -> BearerAuthMiddleware<String> {
+        BearerAuthMiddleware::new("api", |token: &str| (token == "t0ken").then(|| "ann".to_owned()))
+    }
+
+    const BASIC_CHALLENGE: &str = "Basic realm=\"api\", charset=\"UTF-8\"";
+
+    #[test]
+    fn basic_credentials_are_verified() {
+        // The password contains a colon, only the first one ends the user name
+        let authorization = format!("Basic {}", STANDARD.encode("ann:p:ss"));
+        assert_eq!(send(&basic(), Some(&authorization)), (200, None, Some("ann".to_owned())));
+    }
+
+    #[test]
+    fn scheme_is_case_insensitive() {
+        let encoded = STANDARD.encode("ann:p:ss");
+        for scheme in ["basic", "BASIC", "bAsIc"] {
+            let authorization = format!("{} {}", scheme, encoded);
+            assert_eq!(send(&basic(), Some(&authorization)).0, 200, "{}", scheme);
+        }
+        for scheme in ["bearer", "BEARER"] {
+            let authorization = format!("{} t0ken", scheme);
+            assert_eq!(send(&bearer(), Some(&authorization)).0, 200, "{}", scheme);
+        }
+    }
+
+    #[test]
+    fn invalid_basic_credentials_are_challenged() {
+        let invalid = [
+            None,
+            Some(String::new()),
+            Some("Basic".to_owned()),
+            Some("Basic ".to_owned()),
+            Some(format!("Basic{}", STANDARD.encode("ann:p:ss"))),
+            Some(format!("Bearer {}", STANDARD.encode("ann:p:ss"))),
+            Some("Basic !!!not base64".to_owned()),
+            // Not UTF-8
+            Some(format!("Basic {}", STANDARD.encode(b"ann:p\xffss"))),
+            // No colon
+            Some(format!("Basic {}", STANDARD.encode("ann"))),
+            Some(format!("Basic {}", STANDARD.encode("ann:wrong"))),
+        ];
+        for authorization in invalid {
+            assert_eq!(
+                send(&basic(), authorization.as_deref()),
+                (401, Some(BASIC_CHALLENGE.to_owned()), None),
+                "{:?}",
+                authorization
+            );
+        }
+    }
+
+    #[test]
+    fn bearer_challenge_reports_invalid_tokens() {
+        assert_eq!(send(&bearer(), Some("Bearer t0ken")), (200, None, Some("ann".to_owned())));
+        let missing = (401, Some("Bearer realm=\"api\"".to_owned()), None);
+        assert_eq!(send(&bearer(), None), missing);
+        assert_eq!(send(&bearer(), Some("Bearer ")), missing);
+        assert_eq!(send(&bearer(), Some("Basic t0ken")), missing);
+        let invalid = "Bearer realm=\"api\", error=\"invalid_token\", error_description=\"Invalid token\"";
+        assert_eq!(send(&bearer(), Some("Bearer other")), (401, Some(invalid.to_owned()), None));
+    }
+
+    #[test]
+    fn challenge_parameters_are_quoted() {
+        let middleware = BasicAuthMiddleware::new("my \"api\" \\ zone", |_: &str, _: &str| None::<String>);
+        assert_eq!(
+            send(&middleware, None).1.as_deref(),
+            Some("Basic realm=\"my \\\"api\\\" \\\\ zone\", charset=\"UTF-8\"")
+        );
+        // Error descriptions can't be escaped, the characters not allowed are dropped
+        assert_eq!(
+            bearer_challenge("a\"b", Some("bad \"token\"\\ \u{e9}\t!")),
+            "Bearer realm=\"a\\\"b\", error=\"invalid_token\", error_description=\"bad token !\""
+        );
+    }
+}
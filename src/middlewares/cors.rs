@@ -27,6 +27,12 @@ impl CorsMiddleware {
         }
     }
 
+    fn allows_method(&self, method: &Method) -> bool {
+        // HEAD is served by GET routes, so allowing GET allows HEAD.
+        self.allowed_methods.contains(method)
+            || (*method == Method::HEAD && self.allowed_methods.contains(&Method::GET))
+    }
+
     fn add_cors_headers(&self, response: &mut Response, origin: &str) {
         if self.allowed_origins.contains(&origin.to_string())
             || self.allowed_origins.contains(&"*".to_string())
@@ -61,12 +67,17 @@ impl CorsMiddleware {
 impl Middleware for CorsMiddleware {
     fn handle(&self, req: Request, state:State, next: &dyn Handler) -> Response {
         if let Some(origin) = req.headers().get("Origin") {
-            let origin = origin.as_str();
 
-            if req.method().clone() == Method::OPTIONS {
-                // Handle preflight request
+            let requested_method = req.headers().get("Access-Control-Request-Method");
+            if *req.method() == Method::OPTIONS && requested_method.is_some() {
+                // Handle preflight request, only approving methods we allow
                 let mut response = Response::no_content();
-                self.add_cors_headers(&mut response, origin);
+                if requested_method
+                    .and_then(|m| m.parse::<Method>().ok())
+                    .is_some_and(|m| self.allows_method(&m))
+                {
+                    self.add_cors_headers(&mut response, origin);
+                }
                 return response;
             } else {
                 // Handle actual request
@@ -77,7 +88,6 @@ impl Middleware for CorsMiddleware {
         }
 
         // If there's no Origin header, just proceed to the next middleware or handler
-        let response = next.handle(req, state);
-        response
+        next.handle(req, state)
     }
 }
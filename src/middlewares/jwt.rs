@@ -0,0 +1,498 @@
+//! Authentication with JSON Web Tokens (RFC 7519) sent with the Bearer scheme.
+//!
+//! Tokens are verified with a local key, HS256, RS256 or ES256, and their expiry, not-before,
+//! audience and issuer claims are checked. The claims of a valid token are attached to the
+//! request extensions, handlers take them as a `Claims` argument.
+
+use crate::error::Error;
+use crate::extract::FromRequest;
+use crate::handler::Handler;
+use crate::http::{InvalidKey, Request, Response};
+use crate::middleware::Middleware;
+use crate::middlewares::auth::{bearer_challenge, credentials, unauthorized};
+use crate::state::State;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use std::error;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The key verifying tokens, which also fixes the algorithm they must be signed with
+#[derive(Clone)]
+pub struct JwtKey(KeyKind);
+
+#[derive(Clone)]
+enum KeyKind {
+    Hs256(Vec<u8>),
+    Rs256(rsa::pkcs1v15::VerifyingKey<Sha256>),
+    Es256(p256::ecdsa::VerifyingKey),
+}
+
+impl JwtKey {
+    /// A shared secret for HMAC with SHA-256, which should be at least 32 random bytes.
+    /// Fails for an empty secret, which would let anyone sign tokens.
+    pub fn hs256(secret: &[u8]) -> Result<Self, InvalidKey> {
+        if secret.is_empty() {
+            return Err(InvalidKey("empty HS256 secret".to_owned()));
+        }
+        Ok(JwtKey(KeyKind::Hs256(secret.to_vec())))
+    }
+    /// An RSA public key for RSASSA-PKCS1-v1_5 with SHA-256, in PEM as `PUBLIC KEY` or `RSA PUBLIC KEY`
+    pub fn rs256_pem(pem: &str) -> Result<Self, InvalidKey> {
+        use rsa::pkcs1::DecodeRsaPublicKey;
+        use rsa::pkcs8::DecodePublicKey;
+        let key = rsa::RsaPublicKey::from_public_key_pem(pem)
+            .or_else(|_| rsa::RsaPublicKey::from_pkcs1_pem(pem))
+            .map_err(|e| InvalidKey(e.to_string()))?;
+        Ok(JwtKey(KeyKind::Rs256(rsa::pkcs1v15::VerifyingKey::new(key))))
+    }
+    /// A P-256 public key for ECDSA with SHA-256, in PEM as `PUBLIC KEY`
+    pub fn es256_pem(pem: &str) -> Result<Self, InvalidKey> {
+        use p256::pkcs8::DecodePublicKey;
+        let key = p256::ecdsa::VerifyingKey::from_public_key_pem(pem).map_err(|e| InvalidKey(e.to_string()))?;
+        Ok(JwtKey(KeyKind::Es256(key)))
+    }
+
+    /// The `alg` of tokens signed for the key
+    fn algorithm(&self) -> &'static str {
+        match self.0 {
+            KeyKind::Hs256(_) => "HS256",
+            KeyKind::Rs256(_) => "RS256",
+            KeyKind::Es256(_) => "ES256",
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        use p256::ecdsa::signature::Verifier;
+        match &self.0 {
+            KeyKind::Hs256(secret) => {
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC takes keys of any size");
+                mac.update(message);
+                mac.verify_slice(signature).is_ok()
+            }
+            KeyKind::Rs256(key) => rsa::pkcs1v15::Signature::try_from(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+            // JWS signatures are r and s concatenated (RFC 7518 section 3.4)
+            KeyKind::Es256(key) => p256::ecdsa::Signature::from_slice(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+        }
+    }
+}
+
+/// Hides the key material
+impl fmt::Debug for JwtKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JwtKey({})", self.algorithm())
+    }
+}
+
+/// Why a token was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum JwtError {
+    /// Not three base64url parts of JSON objects
+    Malformed,
+    /// Signed with another algorithm than the key is for
+    Algorithm(String),
+    /// Lists extensions in its `crit` header parameter, none of which are supported (RFC 7515 section 4.1.11)
+    Critical,
+    Signature,
+    Expired,
+    NotYetValid,
+    /// No `exp` claim while one is required
+    MissingExpiry,
+    Audience,
+    Issuer,
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JwtError::Malformed => write!(f, "Malformed token"),
+            JwtError::Algorithm(_) => write!(f, "Unexpected algorithm"),
+            JwtError::Critical => write!(f, "Unsupported critical extension"),
+            JwtError::Signature => write!(f, "Invalid signature"),
+            JwtError::Expired => write!(f, "Token expired"),
+            JwtError::NotYetValid => write!(f, "Token not yet valid"),
+            JwtError::MissingExpiry => write!(f, "Token without expiry"),
+            JwtError::Audience => write!(f, "Token for another audience"),
+            JwtError::Issuer => write!(f, "Token from another issuer"),
+        }
+    }
+}
+
+impl error::Error for JwtError {}
+
+/// The claims of a verified token
+#[derive(Debug, Clone, PartialEq)]
+pub struct Claims(Map<String, Value>);
+
+impl Claims {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)
+    }
+    /// Returns the `sub` claim, the principal the token is about
+    pub fn subject(&self) -> Option<&str> {
+        self.0.get("sub").and_then(Value::as_str)
+    }
+    /// Deserializes the claims into `T`
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        T::deserialize(Value::Object(self.0.clone()))
+    }
+}
+
+/// The claims attached by `JwtMiddleware`.
+/// Without the middleware the request is answered with 500 Internal Server Error.
+impl FromRequest for Claims {
+    fn from_request(req: &Request, _state: &State) -> Result<Self, Error> {
+        req.extensions()
+            .get::<Claims>()
+            .cloned()
+            .ok_or_else(|| Error::Internal("No claims, add JwtMiddleware to the router".to_owned()))
+    }
+}
+
+/// Requires a valid JSON Web Token in the Authorization header
+pub struct JwtMiddleware {
+    key: JwtKey,
+    realm: String,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: Duration,
+    require_exp: bool,
+}
+
+impl JwtMiddleware {
+    pub fn new(key: JwtKey) -> Self {
+        JwtMiddleware {
+            key,
+            realm: "api".to_owned(),
+            issuer: None,
+            audience: None,
+            leeway: Duration::from_secs(60),
+            require_exp: true,
+        }
+    }
+    /// Sets the realm of the challenge, default `api`
+    pub fn realm(mut self, realm: impl Into<String>) -> Self {
+        self.realm = realm.into();
+        self
+    }
+    /// Requires the `iss` claim to be the issuer
+    pub fn issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+    /// Requires the `aud` claim to be or contain the audience
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+    /// Sets the clock difference allowed when checking `exp` and `nbf`, default one minute
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+    /// Sets whether tokens without an `exp` claim are rejected, default on
+    pub fn require_exp(mut self, require: bool) -> Self {
+        self.require_exp = require;
+        self
+    }
+
+    /// Verifies the signature and claims of a token, returning its claims
+    pub fn verify(&self, token: &str) -> Result<Claims, JwtError> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(JwtError::Malformed);
+        };
+        // The header and payload are signed as sent, with the dot between them
+        let signed = &token[..header.len() + 1 + payload.len()];
+        let header = decode_object(header)?;
+        // The algorithm is fixed by the key, never chosen by the token
+        let algorithm = header.get("alg").and_then(Value::as_str).unwrap_or("");
+        if algorithm != self.key.algorithm() {
+            return Err(JwtError::Algorithm(algorithm.to_owned()));
+        }
+        // Tokens relying on extensions must be rejected by recipients that don't understand them
+        if header.contains_key("crit") {
+            return Err(JwtError::Critical);
+        }
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| JwtError::Malformed)?;
+        if !self.key.verify(signed.as_bytes(), &signature) {
+            return Err(JwtError::Signature);
+        }
+        let claims = decode_object(payload)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        let leeway = self.leeway.as_secs_f64();
+        match claims.get("exp").map(Value::as_f64) {
+            Some(Some(exp)) if now > exp + leeway => return Err(JwtError::Expired),
+            Some(None) => return Err(JwtError::Malformed),
+            None if self.require_exp => return Err(JwtError::MissingExpiry),
+            _ => {}
+        }
+        match claims.get("nbf").map(Value::as_f64) {
+            Some(Some(nbf)) if now + leeway < nbf => return Err(JwtError::NotYetValid),
+            Some(None) => return Err(JwtError::Malformed),
+            _ => {}
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err(JwtError::Issuer);
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err(JwtError::Audience);
+            }
+        }
+        Ok(Claims(claims))
+    }
+}
+
+impl Middleware for JwtMiddleware {
+    fn handle(&self, mut req: Request, state: State, next: &dyn Handler) -> Response {
+        let Some(token) = credentials(&req, "Bearer") else {
+            return unauthorized(&req, bearer_challenge(&self.realm, None), "Missing token");
+        };
+        match self.verify(token) {
+            Ok(claims) => {
+                req.extensions_mut().insert(claims);
+                next.handle(req, state)
+            }
+            Err(e) => {
+                let message = e.to_string();
+                unauthorized(&req, bearer_challenge(&self.realm, Some(&message)), &message)
+            }
+        }
+    }
+}
+
+/// Decodes a base64url part holding a JSON object
+fn decode_object(part: &str) -> Result<Map<String, Value>, JwtError> {
+    let json = URL_SAFE_NO_PAD.decode(part).map_err(|_| JwtError::Malformed)?;
+    match serde_json::from_slice(&json) {
+        Ok(Value::Object(object)) => Ok(object),
+        _ => Err(JwtError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    /// Not used to sign, tokens can't be verified with it
+    const RSA_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEArQaSwSN3ovxx1jkppJ5A
+b8CA3yzXF+7WrK0NPvlpKmU40o9YUtVmesyp6ePINFfXIHEdLBE5wuc+9Cjpkw99
+pzKpaj6U7H3YCLYcomdxIed1TV2mZWHk315jchKx/XbJdqh8MEZe+JgWDlUAKbRA
+Q0nB9JEsMf7lRSwqQpKGQizIBj6BCdsQWx9jFAuDLQVGLyBOn2ZtnzYGR7eB2Hpr
+q0S1sI7IPZ5kJu+Ocp3awMKv1pUg9XdKST4o9ZHSdsPBb7CquEKHjQpkdFx8YPJt
+SweXlk1aHTgDiAqfelhHwLemPTXcAd4hzDsJgumAXMwkyBtDolIXEJsgCDeEXT/c
+bQIDAQAB
+-----END PUBLIC KEY-----
+";
+
+    /// Signs a token with HS256, whatever its header says
+    fn sign(secret: &[u8], header: &Value, claims: &Value) -> String {
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).unwrap();
+        mac.update(signed.as_bytes());
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+    }
+
+    fn now() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    }
+
+    fn middleware() -> JwtMiddleware {
+        JwtMiddleware::new(JwtKey::hs256(SECRET).unwrap())
+            .issuer("warv")
+            .audience("api")
+            .leeway(Duration::from_secs(60))
+    }
+
+    /// A named token, built from the current time, and a check of the error it is rejected with
+    type Rejected = (&'static str, fn(i64) -> String, fn(&JwtError) -> bool);
+
+    const REJECTED: &[Rejected] = &[
+        (
+            "alg none",
+            |now| {
+                let claims = json!({"iss": "warv", "aud": "api", "exp": now + 600});
+                let token = sign(SECRET, &json!({"alg": "none"}), &claims);
+                // Unsecured tokens have an empty signature
+                format!("{}.", &token[..token.rfind('.').unwrap()])
+            },
+            |e| *e == JwtError::Algorithm("none".to_owned()),
+        ),
+        (
+            "No alg",
+            |now| sign(SECRET, &json!({"typ": "JWT"}), &json!({"iss": "warv", "aud": "api", "exp": now + 600})),
+            |e| *e == JwtError::Algorithm(String::new()),
+        ),
+        (
+            "Signed with another secret",
+            |now| sign(b"another secret", &json!({"alg": "HS256"}), &json!({"iss": "warv", "aud": "api", "exp": now + 600})),
+            |e| *e == JwtError::Signature,
+        ),
+        (
+            "Claims changed after signing",
+            |now| {
+                let token = sign(SECRET, &json!({"alg": "HS256"}), &json!({"iss": "warv", "aud": "api", "exp": now + 600}));
+                let forged = URL_SAFE_NO_PAD.encode(json!({"iss": "warv", "aud": "api", "exp": now + 6000}).to_string());
+                let parts: Vec<&str> = token.split('.').collect();
+                format!("{}.{}.{}", parts[0], forged, parts[2])
+            },
+            |e| *e == JwtError::Signature,
+        ),
+        (
+            "Critical extension",
+            |now| {
+                // Unencoded payloads (RFC 7797) change what is signed
+                let header = json!({"alg": "HS256", "crit": ["b64"], "b64": false});
+                sign(SECRET, &header, &json!({"iss": "warv", "aud": "api", "exp": now + 600}))
+            },
+            |e| *e == JwtError::Critical,
+        ),
+        (
+            "Expired beyond the leeway",
+            |now| sign(SECRET, &json!({"alg": "HS256"}), &json!({"iss": "warv", "aud": "api", "exp": now - 120})),
+            |e| *e == JwtError::Expired,
+        ),
+        (
+            "Not yet valid beyond the leeway",
+            |now| {
+                let claims = json!({"iss": "warv", "aud": "api", "exp": now + 600, "nbf": now + 120});
+                sign(SECRET, &json!({"alg": "HS256"}), &claims)
+            },
+            |e| *e == JwtError::NotYetValid,
+        ),
+        (
+            "Non-numeric exp",
+            |_| sign(SECRET, &json!({"alg": "HS256"}), &json!({"iss": "warv", "aud": "api", "exp": "never"})),
+            |e| *e == JwtError::Malformed,
+        ),
+        (
+            "Non-numeric nbf",
+            |now| {
+                let claims = json!({"iss": "warv", "aud": "api", "exp": now + 600, "nbf": "now"});
+                sign(SECRET, &json!({"alg": "HS256"}), &claims)
+            },
+            |e| *e == JwtError::Malformed,
+        ),
+        (
+            "No exp",
+            |_| sign(SECRET, &json!({"alg": "HS256"}), &json!({"iss": "warv", "aud": "api"})),
+            |e| *e == JwtError::MissingExpiry,
+        ),
+        (
+            "No iss",
+            |now| sign(SECRET, &json!({"alg": "HS256"}), &json!({"aud": "api", "exp": now + 600})),
+            |e| *e == JwtError::Issuer,
+        ),
+        (
+            "Another iss",
+            |now| sign(SECRET, &json!({"alg": "HS256"}), &json!({"iss": "other", "aud": "api", "exp": now + 600})),
+            |e| *e == JwtError::Issuer,
+        ),
+        (
+            "No aud",
+            |now| sign(SECRET, &json!({"alg": "HS256"}), &json!({"iss": "warv", "exp": now + 600})),
+            |e| *e == JwtError::Audience,
+        ),
+        (
+            "Another aud",
+            |now| sign(SECRET, &json!({"alg": "HS256"}), &json!({"iss": "warv", "aud": "web", "exp": now + 600})),
+            |e| *e == JwtError::Audience,
+        ),
+        (
+            "aud array without the audience",
+            |now| {
+                let claims = json!({"iss": "warv", "aud": ["web", "admin"], "exp": now + 600});
+                sign(SECRET, &json!({"alg": "HS256"}), &claims)
+            },
+            |e| *e == JwtError::Audience,
+        ),
+        ("Two parts", |_| "e30.e30".to_owned(), |e| *e == JwtError::Malformed),
+        ("Header not an object", |_| "W10.e30.".to_owned(), |e| *e == JwtError::Malformed),
+    ];
+
+    #[test]
+    fn invalid_tokens_are_rejected() {
+        let middleware = middleware();
+        for (name, token, expected) in REJECTED {
+            match middleware.verify(&token(now())) {
+                Err(e) => assert!(expected(&e), "{}: unexpected error {:?}", name, e),
+                Ok(_) => panic!("{}: accepted", name),
+            }
+        }
+    }
+
+    #[test]
+    fn valid_tokens_are_accepted() {
+        let now = now();
+        let middleware = middleware();
+        let valid = [
+            json!({"sub": "ann", "iss": "warv", "aud": "api", "exp": now + 600}),
+            json!({"sub": "ann", "iss": "warv", "aud": ["web", "api"], "exp": now + 600}),
+            // Within the leeway
+            json!({"sub": "ann", "iss": "warv", "aud": "api", "exp": now - 30, "nbf": now + 30}),
+        ];
+        for claims in valid {
+            let token = sign(SECRET, &json!({"alg": "HS256", "typ": "JWT"}), &claims);
+            let verified = middleware.verify(&token).unwrap_or_else(|e| panic!("{}: {:?}", claims, e));
+            assert_eq!(verified.subject(), Some("ann"));
+        }
+    }
+
+    #[test]
+    fn leeway_can_be_removed() {
+        let now = now();
+        let middleware = middleware().leeway(Duration::ZERO);
+        let token = sign(SECRET, &json!({"alg": "HS256"}), &json!({"iss": "warv", "aud": "api", "exp": now - 30}));
+        assert_eq!(middleware.verify(&token), Err(JwtError::Expired));
+    }
+
+    #[test]
+    fn token_without_exp_is_accepted_when_not_required() {
+        let middleware = JwtMiddleware::new(JwtKey::hs256(SECRET).unwrap()).require_exp(false);
+        let token = sign(SECRET, &json!({"alg": "HS256"}), &json!({"sub": "ann"}));
+        assert_eq!(middleware.verify(&token).unwrap().subject(), Some("ann"));
+    }
+
+    #[test]
+    fn hs256_token_is_rejected_by_an_rs256_key() {
+        // Signed with the public key as the HMAC secret, which anyone can do
+        let middleware = JwtMiddleware::new(JwtKey::rs256_pem(RSA_PUBLIC_KEY).unwrap());
+        let claims = json!({"exp": now() + 600});
+        let token = sign(RSA_PUBLIC_KEY.as_bytes(), &json!({"alg": "HS256"}), &claims);
+        assert_eq!(middleware.verify(&token), Err(JwtError::Algorithm("HS256".to_owned())));
+        // Claiming RS256 doesn't make an HMAC a valid signature
+        let token = sign(RSA_PUBLIC_KEY.as_bytes(), &json!({"alg": "RS256"}), &claims);
+        assert_eq!(middleware.verify(&token), Err(JwtError::Signature));
+    }
+
+    #[test]
+    fn empty_hs256_secret_is_rejected() {
+        assert!(JwtKey::hs256(b"").is_err());
+    }
+}
@@ -14,6 +14,12 @@ impl LoggingMiddleware {
     }
 }
 
+impl Default for LoggingMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Middleware for LoggingMiddleware {
     fn handle(
         &self,
@@ -1,5 +1,13 @@
+mod auth;
 mod cors;
+#[cfg(feature = "jwt")]
+mod jwt;
 mod logging;
+mod session;
 
+pub use auth::{BasicAuthMiddleware, BearerAuthMiddleware};
 pub use cors::CorsMiddleware;
+#[cfg(feature = "jwt")]
+pub use jwt::{Claims, JwtError, JwtKey, JwtMiddleware};
 pub use logging::LoggingMiddleware;
+pub use session::{CookieStore, FileStore, MemoryStore, Session, SessionMiddleware, SessionRecord, SessionStore};
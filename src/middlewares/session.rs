@@ -0,0 +1,617 @@
+//! Sessions kept across requests, identified by a cookie.
+//!
+//! `SessionMiddleware` loads the session of each request from a `SessionStore` and attaches it
+//! to the request extensions, where handlers take it as a `Session` argument. Changes made by the
+//! handler are saved when it returns.
+
+use crate::error::Error;
+use crate::extract::FromRequest;
+use crate::handler::Handler;
+use crate::http::{decode_form, encode_form, random_token, Cookie, Key, Request, Response, SameSite};
+use crate::middleware::Middleware;
+use crate::state::State;
+use log::error;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Random bytes in a session id
+const ID_BYTES: usize = 32;
+/// Largest cookie browsers are required to keep (RFC 6265 section 6.1)
+const MAX_COOKIE: usize = 4096;
+
+/// A session as stored, its id and values
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionRecord {
+    pub id: String,
+    pub values: HashMap<String, String>,
+}
+
+/// Storage of sessions, keyed by the value of the session cookie
+pub trait SessionStore: Send + Sync {
+    /// Loads the session the cookie refers to, `None` if there is none or it has expired
+    fn load(&self, cookie: &str) -> Result<Option<SessionRecord>, Error>;
+    /// Saves the session to expire after `ttl`, returning the value of the session cookie
+    fn save(&self, record: &SessionRecord, ttl: Duration) -> Result<String, Error>;
+    /// Deletes the session with the id
+    fn delete(&self, id: &str) -> Result<(), Error>;
+}
+
+/// The session of a request, taken by handlers as an argument.
+/// Clones share the session, changes are saved after the handler returns.
+#[derive(Debug, Clone)]
+pub struct Session {
+    inner: Arc<Mutex<SessionState>>,
+}
+
+#[derive(Debug, Default)]
+struct SessionState {
+    /// The id the session was loaded with, `None` for a new session
+    id: Option<String>,
+    values: HashMap<String, String>,
+    changed: bool,
+    rotate: bool,
+    destroyed: bool,
+}
+
+impl Session {
+    fn new(record: Option<SessionRecord>) -> Self {
+        let state = match record {
+            Some(record) => SessionState {
+                id: Some(record.id),
+                values: record.values,
+                ..SessionState::default()
+            },
+            None => SessionState::default(),
+        };
+        Session {
+            inner: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SessionState> {
+        // A handler panicking with the lock held can't leave the values half changed
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the id of the session, `None` for a session not saved yet
+    pub fn id(&self) -> Option<String> {
+        self.lock().id.clone()
+    }
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.lock().values.get(key).cloned()
+    }
+    /// Sets a value, returning the earlier value of the key
+    pub fn insert(&self, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        let mut state = self.lock();
+        state.changed = true;
+        state.values.insert(key.into(), value.into())
+    }
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut state = self.lock();
+        let removed = state.values.remove(key);
+        state.changed |= removed.is_some();
+        removed
+    }
+    /// Removes all values, keeping the session
+    pub fn clear(&self) {
+        let mut state = self.lock();
+        state.changed |= !state.values.is_empty();
+        state.values.clear();
+    }
+    pub fn is_empty(&self) -> bool {
+        self.lock().values.is_empty()
+    }
+    /// Moves the session to a new id when it is saved, keeping its values.
+    /// Call it when the privileges of the session change, such as on login,
+    /// so an id planted or seen before can't be used to take over the session.
+    pub fn rotate_id(&self) {
+        self.lock().rotate = true;
+    }
+    /// Ends the session, deleting it from the store and its cookie from the client
+    pub fn destroy(&self) {
+        let mut state = self.lock();
+        state.destroyed = true;
+        state.values.clear();
+    }
+}
+
+/// The session attached by `SessionMiddleware`.
+/// Without the middleware the request is answered with 500 Internal Server Error.
+impl FromRequest for Session {
+    fn from_request(req: &Request, _state: &State) -> Result<Self, Error> {
+        req.extensions()
+            .get::<Session>()
+            .cloned()
+            .ok_or_else(|| Error::Internal("No session, add SessionMiddleware to the router".to_owned()))
+    }
+}
+
+/// Loads and saves the session of each request.
+/// The session cookie is HttpOnly, for the path `/` and SameSite=Lax unless configured otherwise.
+pub struct SessionMiddleware<S> {
+    store: S,
+    cookie_name: String,
+    path: String,
+    domain: Option<String>,
+    secure: bool,
+    same_site: SameSite,
+    ttl: Duration,
+}
+
+impl<S: SessionStore> SessionMiddleware<S> {
+    pub fn new(store: S) -> Self {
+        SessionMiddleware {
+            store,
+            cookie_name: "session".to_owned(),
+            path: "/".to_owned(),
+            domain: None,
+            secure: false,
+            same_site: SameSite::Lax,
+            ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+    /// Sets the name of the session cookie, default `session`
+    pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+        self.cookie_name = name.into();
+        self
+    }
+    /// Sets the path of the session cookie, default `/`
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+    /// Sets whether the session cookie is only sent over HTTPS, default off
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+    /// Sets the SameSite attribute of the session cookie, default Lax
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+    /// Sets how long sessions last after they were last changed, default one day
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    fn cookie(&self, value: String) -> Cookie {
+        let cookie = Cookie::new(self.cookie_name.clone(), value)
+            .path(self.path.clone())
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(self.same_site);
+        match &self.domain {
+            Some(domain) => cookie.domain(domain.clone()),
+            None => cookie,
+        }
+    }
+
+    /// Saves or deletes the session as the handler left it, setting the cookie on the response
+    fn finish(&self, session: &Session, response: &mut Response) -> Result<(), Error> {
+        let state = session.lock();
+        if state.destroyed {
+            if let Some(id) = &state.id {
+                self.store.delete(id)?;
+                response.add_cookie(&self.cookie(String::new()).removal());
+            }
+            return Ok(());
+        }
+        let rotate = state.rotate && state.id.is_some();
+        // New sessions are only saved once they have values
+        if !(state.changed || rotate) || (state.id.is_none() && state.values.is_empty()) {
+            return Ok(());
+        }
+        let id = match &state.id {
+            Some(id) if !rotate => id.clone(),
+            _ => random_token(ID_BYTES),
+        };
+        let record = SessionRecord {
+            id,
+            values: state.values.clone(),
+        };
+        let value = self.store.save(&record, self.ttl)?;
+        if rotate {
+            if let Some(old) = &state.id {
+                self.store.delete(old)?;
+            }
+        }
+        response.add_cookie(&self.cookie(value).max_age(self.ttl));
+        Ok(())
+    }
+}
+
+impl<S: SessionStore> Middleware for SessionMiddleware<S> {
+    fn handle(&self, mut req: Request, state: State, next: &dyn Handler) -> Response {
+        let record = req.cookie(&self.cookie_name).and_then(|cookie| {
+            self.store.load(&cookie).unwrap_or_else(|e| {
+                error!("Failed to load session: {}", e);
+                None
+            })
+        });
+        let session = Session::new(record);
+        req.extensions_mut().insert(session.clone());
+
+        let mut response = next.handle(req, state);
+        if let Err(e) = self.finish(&session, &mut response) {
+            // The changes to the session are lost, the client must not assume they were made
+            error!("Failed to save session: {}", e);
+            return Response::internal_server_error();
+        }
+        response
+    }
+}
+
+/// Sessions kept in memory, lost when the server stops.
+/// Expired sessions are removed when they are loaded and once a minute when sessions are saved.
+#[derive(Debug)]
+pub struct MemoryStore {
+    sessions: Mutex<Sessions>,
+}
+
+#[derive(Debug)]
+struct Sessions {
+    records: HashMap<String, (HashMap<String, String>, Instant)>,
+    swept: Instant,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            sessions: Mutex::new(Sessions {
+                records: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Sessions> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, cookie: &str) -> Result<Option<SessionRecord>, Error> {
+        let mut sessions = self.lock();
+        match sessions.records.get(cookie) {
+            Some((values, expires)) if *expires > Instant::now() => Ok(Some(SessionRecord {
+                id: cookie.to_owned(),
+                values: values.clone(),
+            })),
+            Some(_) => {
+                sessions.records.remove(cookie);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, record: &SessionRecord, ttl: Duration) -> Result<String, Error> {
+        let now = Instant::now();
+        let mut sessions = self.lock();
+        if now.duration_since(sessions.swept) > Duration::from_secs(60) {
+            sessions.records.retain(|_, (_, expires)| *expires > now);
+            sessions.swept = now;
+        }
+        sessions
+            .records
+            .insert(record.id.clone(), (record.values.clone(), now + ttl));
+        Ok(record.id.clone())
+    }
+
+    fn delete(&self, id: &str) -> Result<(), Error> {
+        self.lock().records.remove(id);
+        Ok(())
+    }
+}
+
+/// Sessions kept in the cookie itself, signed so clients can read but not change them.
+/// Nothing is stored on the server, so a session can't be ended before it expires:
+/// a client keeping an old cookie can still use it, also after `Session::destroy` or `Session::rotate_id`.
+/// Sessions must fit a 4 KB cookie.
+#[derive(Debug, Clone)]
+pub struct CookieStore {
+    key: Key,
+}
+
+impl CookieStore {
+    pub fn new(key: Key) -> Self {
+        CookieStore { key }
+    }
+}
+
+impl SessionStore for CookieStore {
+    fn load(&self, cookie: &str) -> Result<Option<SessionRecord>, Error> {
+        let Some(payload) = self.key.verify("session", cookie) else {
+            return Ok(None);
+        };
+        Ok(decode_record(&payload))
+    }
+
+    fn save(&self, record: &SessionRecord, ttl: Duration) -> Result<String, Error> {
+        let value = self.key.sign("session", &encode_record(record, ttl));
+        if value.len() > MAX_COOKIE {
+            return Err(Error::Internal(format!("Session larger than {} bytes", MAX_COOKIE)));
+        }
+        Ok(value)
+    }
+
+    fn delete(&self, _id: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Sessions kept as files in a directory, one per session, named after the SHA-256 of the session id
+/// so the names reveal no ids. Expired sessions are removed when they are loaded, or by `remove_expired`.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Keeps sessions in `dir`, creating it if needed, only accessible by the owner.
+    /// The permissions of an existing directory are left as they are.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    /// Removes the files of expired sessions, returning how many were removed
+    pub fn remove_expired(&self) -> io::Result<usize> {
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "session")
+                && fs::read_to_string(&path).is_ok_and(|content| decode_record(&content).is_none())
+            {
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns the path of the session file.
+    /// Ids come from clients, hashing them keeps them from naming other files.
+    fn path(&self, id: &str) -> PathBuf {
+        // Hex rather than base64, file names may be case-insensitive
+        let name: String = Sha256::digest(id.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+        self.dir.join(format!("{}.session", name))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, cookie: &str) -> Result<Option<SessionRecord>, Error> {
+        let path = self.path(cookie);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let record = decode_record(&content);
+        if record.is_none() {
+            // Expired or unreadable
+            let _ = fs::remove_file(&path);
+        }
+        Ok(record.filter(|r| r.id == cookie))
+    }
+
+    fn save(&self, record: &SessionRecord, ttl: Duration) -> Result<String, Error> {
+        let path = self.path(&record.id);
+        // Written to another file first, so a session is never read half written
+        let temp = path.with_extension(format!("{}.tmp", random_token(8)));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let written = options
+            .open(&temp)
+            .and_then(|mut file| file.write_all(encode_record(record, ttl).as_bytes()));
+        if let Err(e) = written.and_then(|_| fs::rename(&temp, &path)) {
+            let _ = fs::remove_file(&temp);
+            return Err(e.into());
+        }
+        Ok(record.id.clone())
+    }
+
+    fn delete(&self, id: &str) -> Result<(), Error> {
+        match fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Encodes a record with its expiry as a form, the id and expiry under the reserved keys `_id` and `_expires`
+fn encode_record(record: &SessionRecord, ttl: Duration) -> String {
+    let expires = (SystemTime::now() + ttl)
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let expires = expires.to_string();
+    let reserved = [("_id", record.id.as_str()), ("_expires", expires.as_str())];
+    let values = record.values.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+    encode_form(reserved.into_iter().chain(values))
+}
+
+/// Decodes a record encoded by `encode_record`, `None` if it is malformed or expired
+fn decode_record(encoded: &str) -> Option<SessionRecord> {
+    let mut pairs = decode_form(encoded).into_iter();
+    let (_, id) = pairs.next().filter(|(k, _)| k == "_id")?;
+    let (_, expires) = pairs.next().filter(|(k, _)| k == "_expires")?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    if expires.parse::<u64>().ok()? <= now {
+        return None;
+    }
+    Some(SessionRecord {
+        id,
+        values: pairs.collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::HandlerType;
+    use crate::http::Method;
+
+    /// Sends a request with the session cookie through the middleware, the handler acting on the session.
+    /// Returns the Set-Cookie header of the response.
+    fn send<S: SessionStore>(middleware: &SessionMiddleware<S>, cookie: Option<&str>, act: fn(&Session)) -> Option<String> {
+        let mut req = Request::new(Method::GET);
+        if let Some(cookie) = cookie {
+            req.headers_mut().try_insert("Cookie", &format!("session={}", cookie)).unwrap();
+        }
+        let handler = HandlerType::Stateless(Box::new(move |req: Request| {
+            act(req.extensions().get::<Session>().unwrap());
+            Response::ok()
+        }));
+        let response = middleware.handle(req, State::new(), &handler);
+        response.headers().get("Set-Cookie").map(str::to_owned)
+    }
+
+    /// Returns the value of a Set-Cookie header
+    fn value(set_cookie: &str) -> &str {
+        set_cookie.split(';').next().unwrap().split_once('=').unwrap().1
+    }
+
+    /// Starts a session holding `user=ann`, returning its id
+    fn login<S: SessionStore>(middleware: &SessionMiddleware<S>) -> String {
+        let set_cookie = send(middleware, None, |session| {
+            session.insert("user", "ann");
+        });
+        value(&set_cookie.unwrap()).to_owned()
+    }
+
+    #[test]
+    fn new_session_is_saved_once_it_has_values() {
+        let middleware = SessionMiddleware::new(MemoryStore::new());
+        assert_eq!(send(&middleware, None, |_| {}), None);
+        let set_cookie = send(&middleware, None, |session| {
+            session.insert("user", "ann");
+        })
+        .unwrap();
+        assert!(set_cookie.contains("; Path=/; Max-Age=86400; HttpOnly; SameSite=Lax"), "{}", set_cookie);
+        let record = middleware.store.load(value(&set_cookie)).unwrap().unwrap();
+        assert_eq!(record.values.get("user").map(String::as_str), Some("ann"));
+    }
+
+    #[test]
+    fn unknown_id_is_never_used() {
+        let middleware = SessionMiddleware::new(MemoryStore::new());
+        let set_cookie = send(&middleware, Some("planted"), |session| {
+            assert_eq!(session.id(), None);
+            session.insert("user", "ann");
+        })
+        .unwrap();
+        assert_ne!(value(&set_cookie), "planted");
+        assert_eq!(middleware.store.load("planted").unwrap(), None);
+    }
+
+    #[test]
+    fn forged_file_session_is_not_loaded() {
+        let dir = std::env::temp_dir().join(format!("warv-sessions-forged-{}", std::process::id()));
+        let store = FileStore::new(&dir).unwrap();
+        // A record saved under one id is never loaded with another
+        let record = SessionRecord {
+            id: "victim".to_owned(),
+            ..SessionRecord::default()
+        };
+        fs::write(store.path("planted"), encode_record(&record, Duration::from_secs(60))).unwrap();
+        assert_eq!(store.load("planted").unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_session_moves_to_a_new_id() {
+        let middleware = SessionMiddleware::new(MemoryStore::new());
+        let old = login(&middleware);
+        let set_cookie = send(&middleware, Some(&old), |session| session.rotate_id()).unwrap();
+        let new = value(&set_cookie);
+        assert_ne!(new, old);
+        assert_eq!(middleware.store.load(&old).unwrap(), None);
+        let record = middleware.store.load(new).unwrap().unwrap();
+        assert_eq!(record.values.get("user").map(String::as_str), Some("ann"));
+    }
+
+    #[test]
+    fn destroyed_session_is_deleted_with_its_cookie() {
+        let middleware = SessionMiddleware::new(MemoryStore::new());
+        let id = login(&middleware);
+        let set_cookie = send(&middleware, Some(&id), |session| session.destroy()).unwrap();
+        assert!(set_cookie.starts_with("session=; Path=/; Max-Age=0"), "{}", set_cookie);
+        assert_eq!(middleware.store.load(&id).unwrap(), None);
+    }
+
+    #[test]
+    fn expired_record_is_rejected() {
+        let record = SessionRecord {
+            id: "a".to_owned(),
+            values: HashMap::from([("user".to_owned(), "ann".to_owned())]),
+        };
+        assert_eq!(decode_record(&encode_record(&record, Duration::from_secs(60))), Some(record.clone()));
+        assert_eq!(decode_record(&encode_record(&record, Duration::ZERO)), None);
+        assert_eq!(decode_record("_id=a&_expires=1"), None);
+        assert_eq!(decode_record("_expires=99999999999&_id=a"), None);
+        assert_eq!(decode_record("_id=a&_expires=soon"), None);
+    }
+
+    #[test]
+    fn file_names_are_hashed_ids() {
+        let dir = std::env::temp_dir().join(format!("warv-sessions-paths-{}", std::process::id()));
+        let store = FileStore::new(&dir).unwrap();
+        for id in ["../../etc/passwd", "..", "/tmp/x", "a\\..\\b", ""] {
+            let path = store.path(id);
+            assert_eq!(path.parent(), Some(dir.as_path()), "{}", id);
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let hash = name.strip_suffix(".session").unwrap();
+            assert!(hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()), "{}", id);
+        }
+        let record = SessionRecord {
+            id: "../escaped".to_owned(),
+            ..SessionRecord::default()
+        };
+        store.save(&record, Duration::from_secs(60)).unwrap();
+        assert_eq!(store.load("../escaped").unwrap(), Some(record));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cookie_store_rejects_changed_and_oversized_sessions() {
+        let store = CookieStore::new(Key::from_secret(&[7; 32]).unwrap());
+        let mut record = SessionRecord {
+            id: "a".to_owned(),
+            values: HashMap::from([("role".to_owned(), "user".to_owned())]),
+        };
+        let cookie = store.save(&record, Duration::from_secs(60)).unwrap();
+        assert_eq!(store.load(&cookie).unwrap(), Some(record.clone()));
+        let changed = cookie.replace("role=user", "role=admin");
+        assert_ne!(changed, cookie);
+        assert_eq!(store.load(&changed).unwrap(), None);
+        assert_eq!(store.load("role=admin").unwrap(), None);
+
+        record.values.insert("large".to_owned(), "x".repeat(MAX_COOKIE));
+        assert!(matches!(store.save(&record, Duration::from_secs(60)), Err(Error::Internal(_))));
+    }
+}
@@ -0,0 +1,710 @@
+//! HTTP/1.1 request parsing.
+//!
+//! A connection reads into one buffer that is reused for every request on it.
+//! The request head is indexed in place, as ranges into the buffer, and only the
+//! values handed to the `Request` are copied out: the request owns its header values
+//! and body, so the buffer can be reused for the next request. Chunked bodies are decoded,
+//! no other transfer coding is supported.
+//!
+//! In strict mode every message with ambiguous framing is rejected, following the
+//! request smuggling rules of RFC 9112. Otherwise the leniencies the RFC allows are
+//! applied: a bare LF ends a line, and Transfer-Encoding takes precedence over
+//! Content-Length, closing the connection after the response.
+
+use crate::error::ParseError;
+use crate::http::{is_tchar, Method, Request, Uri, UriForm, Version};
+use std::io::{self, Read};
+use std::ops::Range;
+
+/// Bytes read from the stream at a time
+const READ_SIZE: usize = 8 * 1024;
+
+/// Location of the request line and header fields of a request in the connection buffer
+#[derive(Default)]
+pub(crate) struct Head {
+    method: Range<usize>,
+    target: Range<usize>,
+    version: Range<usize>,
+    headers: Vec<(Range<usize>, Range<usize>)>,
+    /// Length of the head including the empty line ending it
+    len: usize,
+    /// Start of the first line not indexed yet, where parsing resumes after more bytes were read.
+    /// Zero until the request line is complete.
+    scanned: usize,
+}
+
+impl Head {
+    /// Returns the header fields as slices of the buffer
+    fn headers<'b>(&'b self, buf: &'b [u8]) -> impl Iterator<Item = (&'b [u8], &'b [u8])> + 'b {
+        self.headers
+            .iter()
+            .map(move |(name, value)| (&buf[name.clone()], &buf[value.clone()]))
+    }
+}
+
+/// How the end of the request body is found
+#[derive(Debug, PartialEq)]
+pub(crate) enum Framing {
+    Length(usize),
+    Chunked,
+}
+
+/// Finds the end of the line starting at `start`, returning the line without its line ending and the start of the next line.
+/// A LF without CR only ends a line when not strict.
+fn line(buf: &[u8], start: usize, strict: bool) -> Result<Option<(Range<usize>, usize)>, ParseError> {
+    let lf = match buf[start..].iter().position(|&b| b == b'\n') {
+        Some(i) => start + i,
+        None => return Ok(None),
+    };
+    if lf > start && buf[lf - 1] == b'\r' {
+        Ok(Some((start..lf - 1, lf + 1)))
+    } else if strict {
+        Err(ParseError::BareLineFeed)
+    } else {
+        Ok(Some((start..lf, lf + 1)))
+    }
+}
+
+fn trim(buf: &[u8], mut range: Range<usize>) -> Range<usize> {
+    while range.start < range.end && matches!(buf[range.start], b' ' | b'\t') {
+        range.start += 1;
+    }
+    while range.end > range.start && matches!(buf[range.end - 1], b' ' | b'\t') {
+        range.end -= 1;
+    }
+    range
+}
+
+/// Splits a header line into name and value, validating both (RFC 9112 section 5)
+fn parse_field(buf: &[u8], field: Range<usize>) -> Result<(Range<usize>, Range<usize>), ParseError> {
+    if matches!(buf[field.start], b' ' | b'\t') {
+        return Err(ParseError::ObsoleteLineFolding);
+    }
+    let colon = match buf[field.clone()].iter().position(|&b| b == b':') {
+        Some(i) => field.start + i,
+        None => return Err(ParseError::MissingColon),
+    };
+    let name = &buf[field.start..colon];
+    if matches!(name.last(), Some(b' ' | b'\t')) {
+        return Err(ParseError::WhitespaceBeforeColon);
+    }
+    if name.is_empty() || !name.iter().all(|&b| is_tchar(b)) {
+        return Err(ParseError::InvalidHeaderName);
+    }
+    let value = trim(buf, colon + 1..field.end);
+    // Visible characters, spaces, tabs and obs-text, no other control characters
+    if buf[value.clone()].iter().any(|&b| (b < 0x20 && b != b'\t') || b == 0x7f) {
+        return Err(ParseError::InvalidHeaderValue);
+    }
+    Ok((field.start..colon, value))
+}
+
+/// Indexes the request head at the start of the buffer.
+/// Returns false if the buffer doesn't hold the complete head yet, the lines indexed so far
+/// are kept and parsing resumes after them on the next call.
+pub(crate) fn parse_head(buf: &[u8], head: &mut Head, strict: bool) -> Result<bool, ParseError> {
+    if head.scanned == 0 {
+        head.headers.clear();
+        let (request_line, next) = match line(buf, 0, strict)? {
+            Some(l) => l,
+            None => return Ok(false),
+        };
+        let mut parts = request_line.clone();
+        let mut next_part = || {
+            let start = parts.start;
+            let end = buf[parts.clone()]
+                .iter()
+                .position(|&b| b == b' ')
+                .map_or(parts.end, |i| start + i);
+            parts.start = (end + 1).min(parts.end);
+            start..end
+        };
+        head.method = next_part();
+        head.target = next_part();
+        head.version = next_part();
+        if head.method.is_empty() || head.target.is_empty() || head.version.is_empty() || head.version.end != request_line.end {
+            return Err(ParseError::BadRequest);
+        }
+        // Only visible ASCII is allowed in the request target
+        if !buf[head.target.clone()].iter().all(|b| b.is_ascii_graphic()) {
+            return Err(ParseError::BadRequest);
+        }
+        head.scanned = next;
+    }
+
+    loop {
+        let (field, next) = match line(buf, head.scanned, strict)? {
+            Some(l) => l,
+            None => return Ok(false),
+        };
+        head.scanned = next;
+        if field.is_empty() {
+            break;
+        }
+        head.headers.push(parse_field(buf, field)?);
+    }
+    head.len = head.scanned;
+    // The next request starts over with its request line
+    head.scanned = 0;
+    Ok(true)
+}
+
+/// Builds the request from an indexed head, returning it with the framing of its body
+/// and whether the connection must be closed after the response.
+pub(crate) fn to_request(buf: &[u8], head: &Head, strict: bool) -> Result<(Request, Framing, bool), ParseError> {
+    let method = std::str::from_utf8(&buf[head.method.clone()])
+        .ok()
+        .and_then(|m| m.parse::<Method>().ok())
+        .ok_or(ParseError::BadRequest)?;
+    let version = match &buf[head.version.clone()] {
+        b"HTTP/1.1" => Version::HTTP1_1,
+        b"HTTP/1.0" => Version::HTTP1_0,
+        v if v.starts_with(b"HTTP/") => return Err(ParseError::VersionNotSupported),
+        _ => return Err(ParseError::BadRequest),
+    };
+    let target = std::str::from_utf8(&buf[head.target.clone()]).map_err(|_| ParseError::BadRequest)?;
+
+    // The form of the target depends on the method (RFC 9112 section 3.2)
+    let uri = match &method {
+        Method::CONNECT => Uri::parse_authority(target),
+        _ => Uri::parse(target),
+    };
+    let uri = match uri {
+        Ok(uri) if uri.fragment().is_some() => return Err(ParseError::InvalidTarget),
+        Ok(uri) if uri.form() == UriForm::Asterisk && method != Method::OPTIONS => {
+            return Err(ParseError::InvalidTarget)
+        }
+        Ok(uri) => uri,
+        // Not strictly, characters URIs don't allow are kept in the path and query
+        Err(_) if !strict && target.starts_with('/') => Uri::new(target),
+        Err(_) => return Err(ParseError::InvalidTarget),
+    };
+
+    let mut request = Request::new(method);
+    request.set_parsed_uri(uri);
+    request.set_version(version);
+    let mut content_length = None;
+    let mut codings: Vec<&[u8]> = Vec::new();
+    let mut host = false;
+    for (name, value) in head.headers(buf) {
+        if name.eq_ignore_ascii_case(b"Content-Length") {
+            // A list of identical values is accepted as one value (RFC 9112 section 6.3)
+            for length in value.split(|&b| b == b',') {
+                let length = parse_content_length(&length[trim(length, 0..length.len())])?;
+                if content_length.is_some_and(|l| l != length) {
+                    return Err(ParseError::DuplicateContentLength);
+                }
+                content_length = Some(length);
+            }
+        } else if name.eq_ignore_ascii_case(b"Host") {
+            if host {
+                return Err(ParseError::DuplicateHost);
+            }
+            host = true;
+        } else if name.eq_ignore_ascii_case(b"Transfer-Encoding") {
+            codings.extend(
+                value
+                    .split(|&b| b == b',')
+                    .map(|c| &c[trim(c, 0..c.len())])
+                    .filter(|c| !c.is_empty()),
+            );
+        }
+        // Names are tokens, so always ASCII
+        let name = std::str::from_utf8(name).map_err(|_| ParseError::InvalidHeaderName)?;
+        request.append_header(name, &header_value(value));
+    }
+    if !host && *request.version() == Version::HTTP1_1 {
+        return Err(ParseError::MissingHost);
+    }
+
+    if codings.is_empty() {
+        return Ok((request, Framing::Length(content_length.unwrap_or(0)), false));
+    }
+    // The body of a request with Transfer-Encoding ends with the chunked coding, applied once (RFC 9112 section 6.3)
+    let chunked = |c: &[u8]| c.eq_ignore_ascii_case(b"chunked");
+    if !codings.last().is_some_and(|c| chunked(c)) || codings.iter().filter(|c| chunked(c)).count() > 1 {
+        return Err(ParseError::InvalidTransferEncoding);
+    }
+    if codings.len() > 1 {
+        return Err(ParseError::UnsupportedTransferEncoding);
+    }
+    let mut close = false;
+    if content_length.is_some() {
+        if strict {
+            return Err(ParseError::ContentLengthWithTransferEncoding);
+        }
+        // Transfer-Encoding overrides Content-Length, the request was possibly crafted to be read differently
+        request.headers_mut().remove("Content-Length");
+        close = true;
+    }
+    if *request.version() == Version::HTTP1_0 {
+        // HTTP/1.0 has no chunked coding, an intermediary may have read the body differently
+        if strict {
+            return Err(ParseError::InvalidTransferEncoding);
+        }
+        close = true;
+    }
+    Ok((request, Framing::Chunked, close))
+}
+
+/// Decodes a header value as UTF-8, or else as ISO-8859-1 so obs-text bytes are kept
+/// as the characters U+0080 to U+00FF instead of being replaced (RFC 9110 section 5.5)
+fn header_value(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(value) => value.to_owned(),
+        Err(_) => value.iter().map(|&b| b as char).collect(),
+    }
+}
+
+/// Parses a Content-Length value, only ASCII digits are allowed
+fn parse_content_length(value: &[u8]) -> Result<usize, ParseError> {
+    if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+        return Err(ParseError::InvalidContentLength);
+    }
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or(ParseError::InvalidContentLength)
+}
+
+/// Parses the chunk-size line of a chunk, returning the size.
+/// Chunk extensions are allowed but ignored, whitespace before them only when not strict.
+fn parse_chunk_size(line: &[u8], strict: bool) -> Result<usize, ParseError> {
+    let digits = line.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+    let (size, mut rest) = line.split_at(digits);
+    if size.is_empty() || size.len() > 2 * std::mem::size_of::<usize>() {
+        return Err(ParseError::InvalidChunkSize);
+    }
+    if !strict {
+        rest = &rest[trim(rest, 0..rest.len()).start..];
+    }
+    if !rest.is_empty() && rest[0] != b';' {
+        return Err(ParseError::InvalidChunkSize);
+    }
+    if rest.iter().any(|&b| (b < 0x20 && b != b'\t') || b == 0x7f) {
+        return Err(ParseError::InvalidChunk);
+    }
+    std::str::from_utf8(size)
+        .ok()
+        .and_then(|s| usize::from_str_radix(s, 16).ok())
+        .ok_or(ParseError::InvalidChunkSize)
+}
+
+/// A decoded body with the end of its message in the buffer
+type DecodedBody = (Vec<u8>, usize);
+
+/// Progress of decoding a chunked body
+enum Chunked {
+    /// Expecting the chunk-size line at the position
+    Size(usize),
+    /// Expecting chunk data of the size, followed by a line ending, at the position
+    Data(usize, usize),
+    /// Expecting a trailer field or the empty line ending the body at the position
+    Trailer(usize),
+}
+
+/// Reads requests from a stream through a buffer reused for the lifetime of the connection
+pub(crate) struct Connection {
+    buffer: Vec<u8>,
+    head: Head,
+    max_head: usize,
+    max_body: usize,
+    strict: bool,
+    close: bool,
+}
+
+impl Connection {
+    pub(crate) fn new(max_head: usize, max_body: usize, strict: bool) -> Self {
+        Connection {
+            buffer: Vec::with_capacity(READ_SIZE),
+            head: Head::default(),
+            max_head,
+            max_body,
+            strict,
+            close: false,
+        }
+    }
+
+    /// Whether the connection must be closed after answering the last request read,
+    /// because its framing was ambiguous
+    pub(crate) fn must_close(&self) -> bool {
+        self.close
+    }
+
+    /// Reads more bytes from the stream into the buffer, returning how many were read
+    fn fill<S: Read>(&mut self, stream: &mut S) -> io::Result<usize> {
+        let len = self.buffer.len();
+        self.buffer.resize(len + READ_SIZE, 0);
+        let read = stream.read(&mut self.buffer[len..]);
+        self.buffer.truncate(len + *read.as_ref().unwrap_or(&0));
+        read
+    }
+
+    /// Reads the next request from the stream.
+    /// Returns `Ok(None)` when the connection is closed between requests.
+    pub(crate) fn next_request<S: Read>(&mut self, stream: &mut S) -> io::Result<Option<Result<Request, ParseError>>> {
+        self.close = false;
+        loop {
+            match parse_head(&self.buffer, &mut self.head, self.strict) {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => return Ok(Some(Err(e))),
+            }
+            if self.buffer.len() >= self.max_head {
+                return Ok(Some(Err(ParseError::HeaderTooLarge)));
+            }
+            if self.fill(stream)? == 0 {
+                return Ok(None);
+            }
+        }
+
+        let (mut request, framing, close) = match to_request(&self.buffer, &self.head, self.strict) {
+            Ok(r) => r,
+            Err(e) => return Ok(Some(Err(e))),
+        };
+        self.close = close;
+        let (body, end) = match framing {
+            Framing::Length(len) => {
+                if len > self.max_body {
+                    return Ok(Some(Err(ParseError::PayloadTooLarge)));
+                }
+                let end = self.head.len + len;
+                while self.buffer.len() < end {
+                    if self.fill(stream)? == 0 {
+                        return Ok(None);
+                    }
+                }
+                (self.buffer[self.head.len..end].to_vec(), end)
+            }
+            Framing::Chunked => match self.read_chunked(stream)? {
+                Some(Ok(body)) => body,
+                Some(Err(e)) => return Ok(Some(Err(e))),
+                None => return Ok(None),
+            },
+        };
+        request.body(body);
+        // Keep what follows, the start of a pipelined request
+        self.buffer.drain(..end);
+        Ok(Some(Ok(request)))
+    }
+
+    /// Decodes the chunked body following the head, returning it with the end of the message in the buffer
+    fn read_chunked<S: Read>(&mut self, stream: &mut S) -> io::Result<Option<Result<DecodedBody, ParseError>>> {
+        let mut body = Vec::new();
+        let mut state = Chunked::Size(self.head.len);
+        loop {
+            let progress = match state {
+                Chunked::Size(pos) => match line(&self.buffer, pos, self.strict) {
+                    Ok(Some((size_line, next))) => parse_chunk_size(&self.buffer[size_line], self.strict)
+                        .and_then(|size| {
+                            // Compared without adding, a chunk size can be close to usize::MAX
+                            if size > self.max_body - body.len() {
+                                return Err(ParseError::PayloadTooLarge);
+                            }
+                            Ok(Some(match size {
+                                0 => Chunked::Trailer(next),
+                                size => Chunked::Data(next, size),
+                            }))
+                        }),
+                    Ok(None) => Ok(None),
+                    Err(e) => Err(e),
+                },
+                Chunked::Data(pos, size) => {
+                    let Some(end) = pos.checked_add(size).filter(|end| end.checked_add(2).is_some()) else {
+                        return Ok(Some(Err(ParseError::PayloadTooLarge)));
+                    };
+                    match self.buffer.get(end..end + 2) {
+                        Some(b"\r\n") => {
+                            body.extend_from_slice(&self.buffer[pos..end]);
+                            Ok(Some(Chunked::Size(end + 2)))
+                        }
+                        Some([b'\n', _]) if !self.strict => {
+                            body.extend_from_slice(&self.buffer[pos..end]);
+                            Ok(Some(Chunked::Size(end + 1)))
+                        }
+                        Some(_) => Err(ParseError::InvalidChunk),
+                        None => Ok(None),
+                    }
+                }
+                Chunked::Trailer(pos) => match line(&self.buffer, pos, self.strict) {
+                    // Trailer fields are validated, but not merged into the headers
+                    Ok(Some((field, next))) if field.is_empty() => return Ok(Some(Ok((body, next)))),
+                    Ok(Some((field, next))) => parse_field(&self.buffer, field).map(|_| Some(Chunked::Trailer(next))),
+                    Ok(None) => Ok(None),
+                    Err(e) => Err(e),
+                },
+            };
+            match progress {
+                Ok(Some(next)) => state = next,
+                Ok(None) => {
+                    // A chunk-size line or trailer field is never longer than a request head
+                    if let Chunked::Size(pos) | Chunked::Trailer(pos) = state {
+                        if self.buffer.len() - pos >= self.max_head {
+                            return Ok(Some(Err(ParseError::InvalidChunk)));
+                        }
+                    }
+                    if self.fill(stream)? == 0 {
+                        return Ok(None);
+                    }
+                }
+                Err(e) => return Ok(Some(Err(e))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads every request of the raw bytes, stopping at the first error
+    fn parse_all(raw: &[u8], strict: bool) -> Vec<Result<(Request, bool), ParseError>> {
+        let mut connection = Connection::new(30 * 1024, 1024 * 1024, strict);
+        let mut stream = raw;
+        let mut requests = Vec::new();
+        while let Some(result) = connection.next_request(&mut stream).unwrap() {
+            let failed = result.is_err();
+            requests.push(result.map(|r| (r, connection.must_close())));
+            if failed {
+                break;
+            }
+        }
+        requests
+    }
+
+    fn parse(raw: &[u8], strict: bool) -> Result<Request, ParseError> {
+        parse_all(raw, strict).remove(0).map(|(r, _)| r)
+    }
+
+    /// A named payload and a check of the error strict mode answers it with
+    type Payload = (&'static str, &'static [u8], fn(&ParseError) -> bool);
+
+    /// Payloads known to desynchronize servers and proxies
+    const DESYNC: &[Payload] = &[
+        (
+            "CL.TE",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 13\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nSMUGGLED",
+            |e| matches!(e, ParseError::ContentLengthWithTransferEncoding),
+        ),
+        (
+            "TE.CL",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n8\r\nSMUGGLED\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::ContentLengthWithTransferEncoding),
+        ),
+        (
+            "TE with whitespace before colon",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding : chunked\r\n\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::WhitespaceBeforeColon),
+        ),
+        (
+            "TE folded onto the previous line",
+            b"POST / HTTP/1.1\r\nHost: a\r\nX: y\r\n Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::ObsoleteLineFolding),
+        ),
+        (
+            "TE not ending with chunked",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked, identity\r\n\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::InvalidTransferEncoding),
+        ),
+        (
+            "TE obfuscated value",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: xchunked\r\n\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::InvalidTransferEncoding),
+        ),
+        (
+            "TE chunked twice",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::InvalidTransferEncoding),
+        ),
+        (
+            "TE in HTTP/1.0",
+            b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::InvalidTransferEncoding),
+        ),
+        (
+            "Conflicting Content-Length",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\nContent-Length: 5\r\n\r\nGET /",
+            |e| matches!(e, ParseError::DuplicateContentLength),
+        ),
+        (
+            "Conflicting Content-Length list",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5, 0\r\n\r\nGET /",
+            |e| matches!(e, ParseError::DuplicateContentLength),
+        ),
+        (
+            "Signed Content-Length",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: +5\r\n\r\nGET /",
+            |e| matches!(e, ParseError::InvalidContentLength),
+        ),
+        (
+            "Hexadecimal Content-Length",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 0x5\r\n\r\nGET /",
+            |e| matches!(e, ParseError::InvalidContentLength),
+        ),
+        (
+            "Bare LF line endings",
+            b"GET / HTTP/1.1\nHost: a\n\n",
+            |e| matches!(e, ParseError::BareLineFeed),
+        ),
+        (
+            "Bare LF hiding a header",
+            b"GET / HTTP/1.1\r\nHost: a\r\nX: y\nContent-Length: 5\r\n\r\nGET /",
+            |e| matches!(e, ParseError::BareLineFeed),
+        ),
+        (
+            "Bare CR in a header value",
+            b"GET / HTTP/1.1\r\nHost: a\r\nX: y\rContent-Length: 5\r\n\r\nGET /",
+            |e| matches!(e, ParseError::InvalidHeaderValue),
+        ),
+        (
+            "NUL in a header value",
+            b"GET / HTTP/1.1\r\nHost: a\r\nX: y\0z\r\n\r\n",
+            |e| matches!(e, ParseError::InvalidHeaderValue),
+        ),
+        (
+            "Duplicate Host",
+            b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n",
+            |e| matches!(e, ParseError::DuplicateHost),
+        ),
+        (
+            "Chunk size overflowing",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n10000000000000005\r\nhello\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::InvalidChunkSize),
+        ),
+        (
+            "Chunk size wrapping the body length",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nFFFFFFFFFFFFFFFF\r\nb\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::PayloadTooLarge),
+        ),
+        (
+            "Chunk size with 0x prefix",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n0x5\r\nhello\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::InvalidChunkSize),
+        ),
+        (
+            "Negative chunk size",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n-1\r\nhello\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::InvalidChunkSize),
+        ),
+        (
+            "Whitespace before chunk extension",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n5 ;ext\r\nhello\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::InvalidChunkSize),
+        ),
+        (
+            "Chunk data longer than its size",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhello\r\n0\r\n\r\n",
+            |e| matches!(e, ParseError::InvalidChunk),
+        ),
+        (
+            "Chunk data ended by a bare LF",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\n0\r\n\r\n",
+            |e| matches!(e, ParseError::InvalidChunk),
+        ),
+    ];
+
+    #[test]
+    fn strict_mode_rejects_desync_payloads() {
+        for (name, payload, expected) in DESYNC {
+            match parse(payload, true) {
+                Err(e) => assert!(expected(&e), "{}: unexpected error {:?}", name, e),
+                Ok(_) => panic!("{}: accepted", name),
+            }
+        }
+    }
+
+    #[test]
+    fn desync_payloads_never_yield_a_second_request() {
+        for (name, payload, _) in DESYNC {
+            for strict in [true, false] {
+                let requests = parse_all(payload, strict);
+                let accepted = requests.iter().filter(|r| r.is_ok()).count();
+                assert!(accepted <= 1, "{}: {} requests read", name, accepted);
+            }
+        }
+    }
+
+    #[test]
+    fn lenient_mode_reads_transfer_encoding_over_content_length() {
+        let raw = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        let (request, close) = parse_all(raw, false).remove(0).unwrap();
+        assert_eq!(request.body, b"hello");
+        assert!(request.headers().get("Content-Length").is_none());
+        assert!(close);
+    }
+
+    #[test]
+    fn lenient_mode_accepts_bare_line_feeds() {
+        let raw = b"POST / HTTP/1.1\nHost: a\nTransfer-Encoding: chunked\n\n5 ;ext\nhello\n0\n\n";
+        let request = parse(raw, false).unwrap();
+        assert_eq!(request.headers().get("Host"), Some("a"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn chunked_body_is_decoded() {
+        let raw = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: t\r\n\r\n";
+        let request = parse(raw, true).unwrap();
+        assert_eq!(request.body, b"hello world");
+    }
+
+    #[test]
+    fn pipelined_requests_follow_a_chunked_body() {
+        let raw = b"POST /a HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhi\r\n0\r\n\r\nGET /b HTTP/1.1\r\nHost: a\r\n\r\n";
+        let requests = parse_all(raw, true);
+        assert_eq!(requests.len(), 2);
+        let (second, close) = requests[1].as_ref().unwrap();
+        assert_eq!(second.uri().path, "/b");
+        assert!(!close);
+    }
+
+    /// Hands out the bytes one read at a time
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((&first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn head_read_a_byte_at_a_time_is_parsed() {
+        let raw = b"GET /a HTTP/1.1\r\nHost: a\r\nX-One: 1\r\n\r\nGET /b HTTP/1.1\r\nHost: b\r\n\r\n";
+        let mut connection = Connection::new(1024, 1024, true);
+        let mut stream = Trickle(raw);
+        let first = connection.next_request(&mut stream).unwrap().unwrap().unwrap();
+        assert_eq!(first.uri().path, "/a");
+        assert_eq!(first.headers().get("X-One"), Some("1"));
+        let second = connection.next_request(&mut stream).unwrap().unwrap().unwrap();
+        assert_eq!(second.uri().path, "/b");
+        assert_eq!(second.headers().get("Host"), Some("b"));
+        assert!(connection.next_request(&mut stream).unwrap().is_none());
+    }
+
+    #[test]
+    fn header_values_that_are_not_utf8_are_kept() {
+        let raw = b"GET / HTTP/1.1\r\nHost: a\r\nX-Latin: caf\xe9\r\nX-Utf8: caf\xc3\xa9\r\n\r\n";
+        let request = parse(raw, true).unwrap();
+        assert_eq!(request.headers().get("X-Latin"), Some("caf\u{e9}"));
+        assert_eq!(request.headers().get("X-Utf8"), Some("caf\u{e9}"));
+    }
+
+    #[test]
+    fn repeated_identical_content_length_is_accepted() {
+        let raw = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 5, 5\r\n\r\nhello";
+        assert_eq!(parse(raw, true).unwrap().body, b"hello");
+    }
+
+    #[test]
+    fn chunked_body_larger_than_the_limit_is_rejected() {
+        let mut connection = Connection::new(1024, 4, true);
+        let raw = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n";
+        let result = connection.next_request(&mut &raw[..]).unwrap().unwrap();
+        assert!(matches!(result, Err(ParseError::PayloadTooLarge)));
+    }
+}
@@ -1,7 +1,13 @@
+use crate::error::{ErrorRenderer, ResponseError};
+use crate::http::IntoResponse;
 use crate::http::Method;
+use crate::http::percent::{decode, decode_path, encode_path};
+use crate::http::uri::normalize_path;
 use crate::http::Request;
+use crate::http::StatusCode;
 use crate::middleware::Middleware;
 
+use log::{error, warn};
 use regex::Regex;
 use std::collections::HashMap;
 use std::error::Error;
@@ -13,17 +19,60 @@ use crate::handler::Handler;
 use crate::http::Response;
 use crate::middlewarewrapper::MiddlewareWrapper;
 use crate::state::State;
-use crate::handler::HandlerType;
+use crate::handler::{Extract, ExtractHandler, HandlerType};
+use crate::routes::{conflict, host_covers, hosts_overlap, ConflictKind, RouteConflict, RouteInfo, RouteKind};
+use crate::urls::{UrlError, UrlMap};
 
 
+/// A registered route
+#[derive(Clone)]
+struct Route {
+    pattern: Regex,
+    template: String,
+    name: Option<String>,
+    handler: Arc<dyn Handler>,
+    /// The handler wrapped in the middlewares of the router
+    chain: Arc<dyn Handler>,
+}
+
+type Routes = HashMap<Method, Vec<Route>>;
+/// A matched route with its decoded path parameters
+type RouteMatch<'a> = (&'a Route, Vec<(String, String)>);
+
+/// How a router treats request paths before matching them against the routes
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum PathNormalization {
+    /// Paths are matched as received, only percent-decoded. `/users` and `/users/` are different routes.
+    #[default]
+    Strict,
+    /// Dot segments and repeated slashes are removed and a trailing slash is optional,
+    /// `/users`, `/users/` and `/a/../users` all match the route `/users`.
+    MatchBoth,
+    /// Like `MatchBoth`, but requests for a non canonical path are answered
+    /// with a 308 redirect to the path the route was registered with.
+    Redirect,
+}
+
 /// Router struct
 #[derive(Clone)]
 pub struct Router {
-    routes: HashMap<Method, Vec<(Regex, Arc<dyn Handler>)>>,
+    routes: Routes,
     middlewares: Vec<Arc<dyn Middleware>>,
     state: State,
     static_dir: Option<String>,
     default_handler: Option<Arc<dyn Handler>>,
+    method_default_handlers: HashMap<Method, Arc<dyn Handler>>,
+    method_not_allowed_handler: Option<Arc<dyn Handler>>,
+    urls: UrlMap,
+    hosts: Vec<(String, Regex)>,
+    normalization: PathNormalization,
+    error_renderer: Option<Arc<ErrorRenderer>>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Router {
@@ -34,54 +83,126 @@ impl Router {
             state: State::new(),
             static_dir: None,
             default_handler: None,
+            method_default_handlers: HashMap::new(),
+            method_not_allowed_handler: None,
+            urls: UrlMap::new(),
+            hosts: Vec::new(),
+            normalization: PathNormalization::default(),
+            error_renderer: None,
         }
     }
     /// Add a stateless route / handler function.
-    pub fn add_stateless_route<F>(&mut self, method: Method, path: &str, handler: F) -> Result<(),Box<dyn Error>> 
+    /// The handler can return anything implementing `IntoResponse`, such as a `Response`
+    /// or a `Result` whose errors are rendered by the router, see `set_error_renderer`.
+    pub fn add_stateless_route<F, R>(&mut self, method: Method, path: &str, handler: F) -> Result<(),Box<dyn Error>> 
     where
-        F: Fn(Request) -> Response + Send + Sync + 'static,
+        F: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
     {
-        let regex = Regex::new(&format!(
-            "^{}$",
-            path.replace("{", "(?P<").replace("}", ">[^/]+)")
-        ))?;
-
-        let handler_type = HandlerType::Stateless(Box::new(handler));
-        self.routes
-        .entry(method)
-        .or_insert_with(Vec::new)
-        .push((regex, Arc::new(handler_type)));
-        Ok(())
+        let handler_type = HandlerType::Stateless(Box::new(move |req| handler(req).into_response()));
+        self.insert_route(method, path, None, Arc::new(handler_type))
     }
     /// Add a stateful route / handler function.
-    pub fn add_stateful_route<F>(&mut self, method: Method, path: &str, handler: F) -> Result<(),Box<dyn Error>> 
+    pub fn add_stateful_route<F, R>(&mut self, method: Method, path: &str, handler: F) -> Result<(),Box<dyn Error>> 
     where
-        F: Fn(Request, State) -> Response + Send + Sync + 'static,
+        F: Fn(Request, State) -> R + Send + Sync + 'static,
+        R: IntoResponse,
     {
-        let regex = Regex::new(&format!(
-            "^{}$",
-            path.replace("{", "(?P<").replace("}", ">[^/]+)")
-        ))?;
-
-        let handler_type = HandlerType::Stateful(Box::new(handler));
-        self.routes
-        .entry(method)
-        .or_insert_with(Vec::new)
-        .push((regex, Arc::new(handler_type)));
-        Ok(())
+        let handler_type = HandlerType::Stateful(Box::new(move |req, state| handler(req, state).into_response()));
+        self.insert_route(method, path, None, Arc::new(handler_type))
     }
     /// Add a route / handler function 
     /// Requires the use of HandlerType Enums
-      pub fn add_route(&mut self, method: Method, path: &str, handler: impl Handler + 'static) -> Result<(),Box<dyn Error>> {
+    pub fn add_route(&mut self, method: Method, path: &str, handler: impl Handler + 'static) -> Result<(),Box<dyn Error>> {
+        self.insert_route(method, path, None, Arc::new(handler))
+    }
+    /// Add a route whose handler takes arguments extracted from the request, see `extract`.
+    /// ```ignore
+    /// router.add_handler(Method::GET, "/users/{id}", |Path(id): Path<u32>, Query(page): Query<Page>| { ... })?;
+    /// ```
+    pub fn add_handler<H, Args>(&mut self, method: Method, path: &str, handler: H) -> Result<(),Box<dyn Error>>
+    where
+        H: ExtractHandler<Args>,
+        Args: 'static,
+    {
+        self.insert_route(method, path, None, Arc::new(Extract::new(handler)))
+    }
+    /// Add a named route whose handler takes arguments extracted from the request, see `add_handler` and `url_for`.
+    pub fn add_named_handler<H, Args>(&mut self, name: &str, method: Method, path: &str, handler: H) -> Result<(),Box<dyn Error>>
+    where
+        H: ExtractHandler<Args>,
+        Args: 'static,
+    {
+        self.insert_route(method, path, Some(name), Arc::new(Extract::new(handler)))
+    }
+    /// Add a named stateless route, see `url_for`.
+    pub fn add_named_stateless_route<F, R>(&mut self, name: &str, method: Method, path: &str, handler: F) -> Result<(),Box<dyn Error>> 
+    where
+        F: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        let handler_type = HandlerType::Stateless(Box::new(move |req| handler(req).into_response()));
+        self.insert_route(method, path, Some(name), Arc::new(handler_type))
+    }
+    /// Add a named stateful route, see `url_for`.
+    pub fn add_named_stateful_route<F, R>(&mut self, name: &str, method: Method, path: &str, handler: F) -> Result<(),Box<dyn Error>> 
+    where
+        F: Fn(Request, State) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        let handler_type = HandlerType::Stateful(Box::new(move |req, state| handler(req, state).into_response()));
+        self.insert_route(method, path, Some(name), Arc::new(handler_type))
+    }
+    /// Add a named route, see `url_for`.
+    /// Requires the use of HandlerType Enums
+    pub fn add_named_route(&mut self, name: &str, method: Method, path: &str, handler: impl Handler + 'static) -> Result<(),Box<dyn Error>> {
+        self.insert_route(method, path, Some(name), Arc::new(handler))
+    }
+    fn insert_route(&mut self, method: Method, path: &str, name: Option<&str>, handler: Arc<dyn Handler>) -> Result<(),Box<dyn Error>> {
         let regex = Regex::new(&format!(
             "^{}$",
             path.replace("{", "(?P<").replace("}", ">[^/]+)")
         ))?;
+        for existing in self.routes.get(&method).into_iter().flatten() {
+            if let Some(kind) = conflict(path, &existing.template) {
+                let error = RouteConflict {
+                    method: method.clone(),
+                    path: path.to_owned(),
+                    existing: existing.template.clone(),
+                    kind,
+                };
+                if kind != ConflictKind::Ambiguous {
+                    return Err(error.into());
+                }
+                warn!("{}", error);
+            }
+        }
+        if let Some(name) = name {
+            if self.urls.contains(name) {
+                return Err(format!("A route named \"{}\" already exists", name).into());
+            }
+            self.urls.insert(name, path);
+        }
         self.routes
             .entry(method)
-            .or_insert_with(Vec::new)
-            .push((regex, Arc::new(handler)));
-            Ok(())
+            .or_default()
+            .push(Route {
+                pattern: regex,
+                template: path.to_owned(),
+                name: name.map(str::to_owned),
+                chain: compose(&self.middlewares, &self.error_renderer, &handler),
+                handler,
+            });
+        Ok(())
+    }
+    /// Generate the URL of a named route.
+    /// `params` fill the `{param}` placeholders of the route, any others become query parameters.
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        self.urls.url_for(name, params)
+    }
+    /// Returns the named routes of this router
+    pub fn urls(&self) -> &UrlMap {
+        &self.urls
     }
     /// Add a middleware
     /// Multiple middlewares can be added.
@@ -90,75 +211,588 @@ impl Router {
         M: Middleware + 'static,
     {
         self.middlewares.push(Arc::new(middleware));
+        self.recompose();
+    }
+    /// Set how the errors returned by handlers are turned into responses.
+    /// The renderer is given the error and the Accept header of the request.
+    /// Without it every error renders itself with `ResponseError::error_response`,
+    /// a JSON, HTML or plain text page of its status and message.
+    pub fn set_error_renderer<F>(&mut self, renderer: F)
+    where
+        F: Fn(&dyn ResponseError, Option<&str>) -> Response + Send + Sync + 'static,
+    {
+        self.error_renderer = Some(Arc::new(renderer));
+        self.recompose();
     }
-    /// Add a state to the router and the defined routes
-    //TODO 
+    /// Keeps the prebuilt chains of the existing routes in sync with the middlewares and error renderer
+    fn recompose(&mut self) {
+        for route in self.routes.values_mut().flatten() {
+            route.chain = compose(&self.middlewares, &self.error_renderer, &route.handler);
+        }
+    }
+    /// Validates the configuration of the router: the static directory exists and every
+    /// state type a handler takes has been added. Called by `Server::add_router`.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = &self.static_dir {
+            if !Path::new(dir).is_dir() {
+                return Err(format!("Static directory \"{}\" is not a directory", dir).into());
+            }
+        }
+        if self.routes.is_empty() && self.static_dir.is_none() && self.default_handler.is_none()
+            && self.method_default_handlers.is_empty()
+        {
+            warn!("Router has no routes, static directory or default handler");
+        }
+        // Every state type a handler takes must have been added
+        for (method, routes) in &self.routes {
+            for route in routes {
+                if let Some(missing) = route.handler.required_state().iter().find(|t| !self.state.contains(t)) {
+                    return Err(format!(
+                        "Route {} {} requires state of type {}, add it with set_state",
+                        method, route.template, missing.name
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Add a state value to the router, shared by all its handlers.
+    /// A router holds one value per type, adding a value of the same type replaces the earlier one.
     pub fn set_state<T: 'static + Send + Sync>(&mut self, state:T )
     {
         self.state.set(state);
     }
+    /// Set how request paths are normalized before matching, see `PathNormalization`
+    pub fn set_path_normalization(&mut self, normalization: PathNormalization) {
+        self.normalization = normalization;
+    }
     /// Serve a static directory
     pub fn set_static_dir(&mut self, dir: String) {
         self.static_dir = Some(dir);
     }
     /// Set a default route for all requests missing a route
-    pub fn set_default_handler<F>(&mut self, handler: impl Handler + 'static)
-    where
-        F: Fn(Request, State) -> Response + Send + Sync + 'static,
-    {
+    pub fn set_default_handler(&mut self, handler: impl Handler + 'static) {
         self.default_handler = Some(Arc::new(handler));
     }
+    /// Set a default route for requests of the method missing a route.
+    /// Takes precedence over the handler set with `set_default_handler`.
+    pub fn set_method_default_handler(&mut self, method: Method, handler: impl Handler + 'static) {
+        self.method_default_handlers.insert(method, Arc::new(handler));
+    }
+    /// Set the handler answering requests for a path that has routes, but not for the method of the request,
+    /// when neither the static directory nor a default handler answers them.
+    /// The Allow header listing the methods of the path is added to its response.
+    /// Without it the router answers with a 405 Method Not Allowed page.
+    pub fn set_method_not_allowed_handler(&mut self, handler: impl Handler + 'static) {
+        self.method_not_allowed_handler = Some(Arc::new(handler));
+    }
 
-    /// The main router component managing the requests received
-    pub fn handle_request(&self, mut req: Request) -> Option<Response> {
-        // Check for route match
-        if let Some(handlers) = self.routes.get(&req.method()) {
-            for (pattern, handler) in handlers {
-                if let Some(captures) = pattern.captures(req.uri().path()) {
-                    let mut params = HashMap::new();
-                    for name in pattern.capture_names().flatten() {
-                        if let Some(value) = captures.name(name) {
-                            params.insert(name.to_string(), value.as_str().to_string());
-                        }
+    /// Bind the router to a host, making it a virtual host.
+    /// Accepts exact hosts `example.com`, wildcard subdomains `*.example.com`
+    /// and captured labels `{tenant}.example.com`, available through `Uri::host_param` and `HostParams<T>`.
+    /// A router can be bound to several hosts, a router without hosts serves every host.
+    pub fn add_host(&mut self, pattern: &str) -> Result<(), Box<dyn Error>> {
+        let pattern = pattern.to_ascii_lowercase();
+        let labels: Vec<String> = pattern
+            .split('.')
+            .enumerate()
+            .map(|(i, label)| {
+                if i == 0 && label == "*" {
+                    r"[^.]+(?:\.[^.]+)*".to_owned()
+                } else if label.starts_with('{') && label.ends_with('}') {
+                    format!("(?P<{}>[^.]+)", &label[1..label.len() - 1])
+                } else {
+                    regex::escape(label)
+                }
+            })
+            .collect();
+        let regex = Regex::new(&format!("^{}$", labels.join(r"\.")))?;
+        self.hosts.push((pattern, regex));
+        Ok(())
+    }
+    /// Returns the host patterns the router is bound to
+    pub fn hosts(&self) -> Vec<&str> {
+        self.hosts.iter().map(|(p, _)| p.as_str()).collect()
+    }
+    /// Returns true if the router is bound to hosts
+    pub fn is_virtual_host(&self) -> bool {
+        !self.hosts.is_empty()
+    }
+    /// Matches the host against the host patterns of the router, returning the captured labels.
+    pub(crate) fn match_host(&self, host: Option<&str>) -> Option<Vec<(String, String)>> {
+        if self.hosts.is_empty() {
+            return Some(Vec::new());
+        }
+        let host = host?;
+        self.hosts.iter().find_map(|(_, pattern)| {
+            let captures = pattern.captures(host)?;
+            Some(
+                pattern
+                    .capture_names()
+                    .flatten()
+                    .filter_map(|name| Some((name.to_owned(), captures.name(name)?.as_str().to_owned())))
+                    .collect(),
+            )
+        })
+    }
+    /// Returns the registered routes, ordered by method and then registration order.
+    /// Static directories and the default handler are listed after the routes.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        let middlewares: Vec<String> = self
+            .middlewares
+            .iter()
+            .map(|m| m.name().to_owned())
+            .collect();
+        let hosts: Vec<String> = self.hosts.iter().map(|(p, _)| p.clone()).collect();
+        let mut methods: Vec<&Method> = self.routes.keys().collect();
+        methods.sort_by_key(|m| m.as_str());
+
+        let mut routes = Vec::new();
+        for method in methods {
+            for route in &self.routes[method] {
+                routes.push(RouteInfo {
+                    method: Some(method.clone()),
+                    path: route.template.clone(),
+                    name: route.name.clone(),
+                    middlewares: middlewares.clone(),
+                    hosts: hosts.clone(),
+                    kind: RouteKind::Handler,
+                });
+            }
+        }
+        if let Some(dir) = &self.static_dir {
+            routes.push(RouteInfo {
+                method: None,
+                path: "/*".to_owned(),
+                name: None,
+                middlewares: Vec::new(),
+                hosts: hosts.clone(),
+                kind: RouteKind::StaticDir(dir.clone()),
+            });
+        }
+        let mut default_methods: Vec<&Method> = self.method_default_handlers.keys().collect();
+        default_methods.sort_by_key(|m| m.as_str());
+        for method in default_methods {
+            routes.push(RouteInfo {
+                method: Some(method.clone()),
+                path: "*".to_owned(),
+                name: None,
+                middlewares: Vec::new(),
+                hosts: hosts.clone(),
+                kind: RouteKind::Default,
+            });
+        }
+        if self.default_handler.is_some() {
+            routes.push(RouteInfo {
+                method: None,
+                path: "*".to_owned(),
+                name: None,
+                middlewares: Vec::new(),
+                hosts: hosts.clone(),
+                kind: RouteKind::Default,
+            });
+        }
+        routes
+    }
+
+    /// Checks the routes of this router against a router that is tried before it.
+    /// Routes that can never be reached are returned as errors, overlapping routes are logged.
+    /// A route is only unreachable if the earlier router serves every host this router serves.
+    pub(crate) fn check_conflicts(&self, earlier: &Router) -> Result<(), RouteConflict> {
+        // Routers serving different hosts never compete for a request
+        if !self.shares_hosts(earlier) {
+            return Ok(());
+        }
+        let covered = earlier.covers_hosts(self);
+        for (method, routes) in &self.routes {
+            for route in routes {
+                let mut conflicts = Vec::new();
+                if earlier.default_handler.is_some() {
+                    conflicts.push(("*".to_owned(), ConflictKind::Shadowed));
+                }
+                for existing in earlier.routes.get(method).into_iter().flatten() {
+                    if let Some(kind) = conflict(&route.template, &existing.template) {
+                        conflicts.push((existing.template.clone(), kind));
                     }
-                    req.add_params(params);
-
-                    // Apply middlewares in sequence
-                    let mut final_handler: Arc<dyn Handler> = handler.clone();
-                    for middleware in self.middlewares.iter().rev() {
-                        final_handler = Arc::new(MiddlewareWrapper {
-                            middleware: Arc::clone(middleware),
-                            next: final_handler.clone(),
-                        });
+                }
+                for (existing, kind) in conflicts {
+                    // On hosts only this router serves the route is still reached
+                    let kind = if covered { kind } else { ConflictKind::Ambiguous };
+                    let error = RouteConflict {
+                        method: method.clone(),
+                        path: route.template.clone(),
+                        existing,
+                        kind,
+                    };
+                    if kind != ConflictKind::Ambiguous {
+                        return Err(error);
                     }
+                    warn!("{}", error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if some host is served by both routers
+    fn shares_hosts(&self, other: &Router) -> bool {
+        self.hosts.is_empty()
+            || other.hosts.is_empty()
+            || self.hosts.iter().any(|(p, _)| other.hosts.iter().any(|(q, _)| hosts_overlap(p, q)))
+    }
+
+    /// Returns true if this router serves every host the other router serves
+    fn covers_hosts(&self, other: &Router) -> bool {
+        self.hosts.is_empty()
+            || (!other.hosts.is_empty()
+                && other.hosts.iter().all(|(q, _)| self.hosts.iter().any(|(p, _)| host_covers(p, q))))
+    }
+
+    /// Finds the first route of the methods matching the path, returning it with its decoded parameters
+    fn find_route(&self, methods: &[Method], path: &str) -> Option<RouteMatch<'_>> {
+        for route in methods.iter().filter_map(|m| self.routes.get(m)).flatten() {
+            if let Some(captures) = route.pattern.captures(path) {
+                let mut params = Vec::new();
+                for name in route.pattern.capture_names().flatten() {
+                    if let Some(value) = captures.name(name) {
+                        let value = value.as_str();
+                        params.push((name.to_string(), decode(value).unwrap_or_else(|| value.to_string())));
+                    }
+                }
+                return Some((route, params));
+            }
+        }
+        None
+    }
+
+    /// Like `find_route`, also trying the path with the trailing slash toggled unless the router is strict.
+    /// Returns the path that matched.
+    fn lookup(&self, methods: &[Method], path: &str) -> Option<(String, RouteMatch<'_>)> {
+        if let Some(found) = self.find_route(methods, path) {
+            return Some((path.to_owned(), found));
+        }
+        if self.normalization == PathNormalization::Strict || path == "/" {
+            return None;
+        }
+        let alternate = match path.strip_suffix('/') {
+            Some(stripped) => stripped.to_owned(),
+            None => format!("{}/", path),
+        };
+        self.find_route(methods, &alternate).map(|found| (alternate, found))
+    }
+
+    /// The main router component managing the requests received.
+    /// Returns `None` when the router has no route for the request.
+    pub fn handle_request(&self, req: Request) -> Option<Response> {
+        match self.route(req) {
+            Routed::Response(response) => Some(response),
+            Routed::MethodNotAllowed(req, allowed) => {
+                let accept = req.headers().get("Accept");
+                let mut response = Response::error_page(StatusCode::MethodNotAlloed, accept);
+                response.insert_header("Allow".to_owned(), allow_header(&allowed));
+                Some(response)
+            }
+            Routed::NotFound(_) => None,
+        }
+    }
+
+    pub(crate) fn route(&self, mut req: Request) -> Routed {
+        // Virtual hosts only serve their own hosts
+        let host_params = match self.match_host(req.host().as_deref()) {
+            Some(params) => params,
+            None => return Routed::NotFound(req),
+        };
 
-                    return Some(final_handler.handle(req, /*params,*/ self.state.clone()));
+        // Paths are matched percent-decoded and, unless strict, normalized
+        let decoded = match decode_path(req.uri().path()) {
+            Some(path) => path,
+            None => return Routed::Response(Response::bad_request()),
+        };
+        let path = match self.normalization {
+            PathNormalization::Strict => decoded.clone(),
+            _ => normalize_path(&decoded),
+        };
+
+        // Check for route match, HEAD requests fall back to the GET routes
+        let mut methods = vec![req.method().clone()];
+        if *req.method() == Method::HEAD {
+            methods.push(Method::GET);
+        }
+        if let Some((matched_path, (route, route_params))) = self.lookup(&methods, &path) {
+            if self.normalization == PathNormalization::Redirect && matched_path != decoded {
+                let mut location = encode_path(&matched_path);
+                if let Some(query) = &req.uri().raw_query {
+                    location = format!("{}?{}", location, query);
                 }
+                let mut response = Response::new(StatusCode::PermanentRedirect);
+                response.insert_header("Location".to_owned(), location);
+                return Routed::Response(response);
             }
+            req.set_host_params(host_params);
+            req.add_params(route_params);
+            req.set_urls(self.urls.clone());
+
+            // The middlewares are applied through the chain built at registration
+            return Routed::Response(route.chain.handle(req, self.state.clone()));
         }
 
-        // Handle static file serving TODO make sure directory traversal doesn't work
+        // Handle static file serving, dot segments are always removed so the path can't leave the directory
         if let Some(dir) = &self.static_dir {
-            let path = Path::new(dir).join(req.uri().path().trim_start_matches('/'));
+            let path = Path::new(dir).join(normalize_path(&decoded).trim_start_matches('/'));
             if path.is_file() {
                 match fs::read(&path) {
                     Ok(file_content) => {
                         let mut response = Response::ok();
                         response.body(file_content);
-                        return Some(response);
+                        return Routed::Response(response);
                     }
                     Err(_) => {
-                        return Some(Response::internal_server_error());
+                        return Routed::Response(Response::internal_server_error());
                     }
                 }
             }
         }
 
-        // Call the default handler if set TODO add method check?
-        if let Some(handler) = &self.default_handler {
-            return Some(handler.handle(req , self.state.clone()));
+        // Call the default handler of the method, or the default handler for all methods
+        let default_handler = self
+            .method_default_handlers
+            .get(req.method())
+            .or(self.default_handler.as_ref());
+        if let Some(handler) = default_handler {
+            req.set_host_params(host_params);
+            return Routed::Response(self.handle_rendered(handler.as_ref(), req));
         }
-        None
-        //Response::not_found()
+
+        // No fallback answered, but the path has routes for other methods
+        let mut allowed: Vec<Method> = self
+            .routes
+            .keys()
+            .filter(|m| self.lookup(&[(*m).clone()], &path).is_some())
+            .cloned()
+            .collect();
+        if !allowed.is_empty() {
+            if allowed.contains(&Method::GET) && !allowed.contains(&Method::HEAD) {
+                allowed.push(Method::HEAD);
+            }
+            allowed.sort_by_key(|m| m.as_str().to_owned());
+            if let Some(handler) = &self.method_not_allowed_handler {
+                req.set_host_params(host_params);
+                let mut response = self.handle_rendered(handler.as_ref(), req);
+                response.insert_header("Allow".to_owned(), allow_header(&allowed));
+                return Routed::Response(response);
+            }
+            return Routed::MethodNotAllowed(req, allowed);
+        }
+        Routed::NotFound(req)
+    }
+
+    /// Calls a handler outside the middleware chain, rendering the error it returns
+    fn handle_rendered(&self, handler: &dyn Handler, req: Request) -> Response {
+        RenderErrors::handle_with(handler, &self.error_renderer, req, self.state.clone())
+    }
+
+    /// Returns the state of the router, also given to the server fallbacks for requests it routed
+    pub(crate) fn state(&self) -> &State {
+        &self.state
+    }
+}
+
+/// Outcome of routing a request through a router.
+/// Requests the router doesn't answer are handed back unchanged.
+pub(crate) enum Routed {
+    Response(Response),
+    /// The path has routes for other methods, listed as the allowed methods
+    MethodNotAllowed(Request, Vec<Method>),
+    NotFound(Request),
+}
+
+/// Formats the methods as the value of an Allow header
+pub(crate) fn allow_header(methods: &[Method]) -> String {
+    methods.iter().map(|m| m.as_str()).collect::<Vec<&str>>().join(", ")
+}
+
+/// Innermost handler of a chain, rendering the error a handler returns before the middlewares see its response
+struct RenderErrors {
+    handler: Arc<dyn Handler>,
+    renderer: Option<Arc<ErrorRenderer>>,
+}
+
+impl RenderErrors {
+    fn handle_with(handler: &dyn Handler, renderer: &Option<Arc<ErrorRenderer>>, req: Request, state: State) -> Response {
+        let accept = req.headers().get("Accept").map(str::to_owned);
+        let mut response = handler.handle(req, state);
+        let error = match response.shared_error() {
+            Some(error) => error,
+            None => return response,
+        };
+        if error.status().as_u16() >= 500 {
+            error!("Handler failed: {}", error);
+        }
+        let rendered = match renderer {
+            Some(render) => render(&*error, accept.as_deref()),
+            None => error.error_response(accept.as_deref()),
+        };
+        // Headers the handler added, such as a challenge or Retry-After, are kept
+        response.replace_content(rendered);
+        response
+    }
+}
+
+impl Handler for RenderErrors {
+    fn handle(&self, req: Request, state: State) -> Response {
+        RenderErrors::handle_with(&*self.handler, &self.renderer, req, state)
+    }
+}
+
+/// Wraps the handler in the error rendering and the middlewares, the first middleware outermost
+fn compose(
+    middlewares: &[Arc<dyn Middleware>],
+    renderer: &Option<Arc<ErrorRenderer>>,
+    handler: &Arc<dyn Handler>,
+) -> Arc<dyn Handler> {
+    let mut chain: Arc<dyn Handler> = Arc::new(RenderErrors {
+        handler: handler.clone(),
+        renderer: renderer.clone(),
+    });
+    for middleware in middlewares.iter().rev() {
+        chain = Arc::new(MiddlewareWrapper {
+            middleware: Arc::clone(middleware),
+            next: chain,
+        });
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Uri;
+
+    fn router(routes: &[(Method, &str)]) -> Router {
+        let mut router = Router::new();
+        for (method, path) in routes {
+            router.add_stateless_route(method.clone(), path, |_| Response::ok()).unwrap();
+        }
+        router
+    }
+
+    fn conflict_kind(error: Box<dyn Error>) -> ConflictKind {
+        error.downcast_ref::<RouteConflict>().expect("a route conflict").kind
+    }
+
+    #[test]
+    fn unreachable_routes_are_rejected() {
+        let mut router = router(&[(Method::GET, "/users/{id}")]);
+        let error = router.add_stateless_route(Method::GET, "/users/me", |_| Response::ok()).unwrap_err();
+        assert_eq!(conflict_kind(error), ConflictKind::Shadowed);
+        let error = router.add_stateless_route(Method::GET, "/users/{name}", |_| Response::ok()).unwrap_err();
+        assert_eq!(conflict_kind(error), ConflictKind::Duplicate);
+        // Other methods have their own routes
+        router.add_stateless_route(Method::POST, "/users/me", |_| Response::ok()).unwrap();
+    }
+
+    #[test]
+    fn overlapping_routes_are_accepted_in_order() {
+        // The static route registered first is still reached, the parameter gets the other paths
+        let mut router = router(&[(Method::GET, "/users/me")]);
+        router.add_stateless_route(Method::GET, "/users/{id}", |_| Response::no_content()).unwrap();
+        for (path, status) in [("/users/me", 200), ("/users/7", 204)] {
+            let mut req = Request::new(Method::GET);
+            req.set_uri(path);
+            assert_eq!(router.handle_request(req).unwrap().status().as_u16(), status, "{}", path);
+        }
+    }
+
+    fn virtual_host(hosts: &[&str], routes: &[(Method, &str)]) -> Router {
+        let mut router = router(routes);
+        for host in hosts {
+            router.add_host(host).unwrap();
+        }
+        router
+    }
+
+    #[test]
+    fn routes_are_only_unreachable_on_covered_hosts() {
+        let route = [(Method::GET, "/users")];
+        // The earlier router serves every host of the later one
+        for (earlier, later) in [
+            (&[][..], &["api.example.com"][..]),
+            (&["*.example.com"], &["api.example.com"]),
+            (&["{tenant}.example.com"], &["api.example.com"]),
+            (&["api.example.com", "*.example.org"], &["api.example.com", "www.example.org"]),
+        ] {
+            let result = virtual_host(later, &route).check_conflicts(&virtual_host(earlier, &route));
+            assert_eq!(result.unwrap_err().kind, ConflictKind::Duplicate, "{:?} before {:?}", earlier, later);
+        }
+        // The later router still serves some hosts, or none are shared
+        for (earlier, later) in [
+            (&["api.example.com"][..], &["*.example.com"][..]),
+            (&["api.example.com"], &[][..]),
+            (&["api.example.com"], &["api.example.com", "www.example.com"]),
+            (&["example.com"], &["*.example.com"]),
+            (&["{tenant}.example.com"], &["v1.api.example.com"]),
+        ] {
+            let result = virtual_host(later, &route).check_conflicts(&virtual_host(earlier, &route));
+            assert!(result.is_ok(), "{:?} before {:?}", earlier, later);
+        }
+    }
+
+    #[test]
+    fn earlier_default_handler_shadows_every_route() {
+        let mut earlier = virtual_host(&["*.example.com"], &[]);
+        earlier.set_default_handler(HandlerType::Stateless(Box::new(|_| Response::ok())));
+        let error = virtual_host(&["api.example.com"], &[(Method::GET, "/users")])
+            .check_conflicts(&earlier)
+            .unwrap_err();
+        assert_eq!((error.existing.as_str(), error.kind), ("*", ConflictKind::Shadowed));
+    }
+
+    /// Routes the request target through a router with the routes `/users` and `/docs/`.
+    /// Returns the status and Location header of the response, `None` if no route matched.
+    fn normalized(normalization: PathNormalization, target: &str) -> Option<(u16, Option<String>)> {
+        let mut router = router(&[(Method::GET, "/users"), (Method::GET, "/docs/"), (Method::GET, "/caf\u{e9}")]);
+        router.set_path_normalization(normalization);
+        let mut req = Request::new(Method::GET);
+        req.set_parsed_uri(Uri::parse(target).unwrap());
+        let response = router.handle_request(req)?;
+        Some((response.status().as_u16(), response.headers().get("Location").map(str::to_owned)))
+    }
+
+    #[test]
+    fn strict_paths_match_as_received() {
+        let strict = |target| normalized(PathNormalization::Strict, target);
+        assert_eq!(strict("/users"), Some((200, None)));
+        assert_eq!(strict("/docs/"), Some((200, None)));
+        assert_eq!(strict("/caf%C3%A9"), Some((200, None)));
+        for target in ["/users/", "/docs", "//users", "/a/../users", "/./users"] {
+            assert_eq!(strict(target), None, "{}", target);
+        }
+    }
+
+    #[test]
+    fn match_both_ignores_the_trailing_slash_and_dot_segments() {
+        for target in ["/users", "/users/", "//users", "/a/../users", "/./users/", "/docs", "/docs/", "/caf%C3%A9/"] {
+            assert_eq!(normalized(PathNormalization::MatchBoth, target), Some((200, None)), "{}", target);
+        }
+        assert_eq!(normalized(PathNormalization::MatchBoth, "/users/7"), None);
+    }
+
+    #[test]
+    fn redirect_sends_other_paths_to_the_route_path() {
+        let redirect = |target| normalized(PathNormalization::Redirect, target);
+        assert_eq!(redirect("/users"), Some((200, None)));
+        assert_eq!(redirect("/docs/?page=2"), Some((200, None)));
+        for (target, location) in [
+            ("/users/", "/users"),
+            ("/users/?page=2&sort=asc", "/users?page=2&sort=asc"),
+            ("/docs?page=2", "/docs/?page=2"),
+            ("/a/../users?q=x", "/users?q=x"),
+            ("//users", "/users"),
+            ("/caf%C3%A9/", "/caf%C3%A9"),
+        ] {
+            assert_eq!(redirect(target), Some((308, Some(location.to_owned()))), "{}", target);
+        }
+        assert_eq!(redirect("/users/7"), None);
     }
 }
@@ -0,0 +1,361 @@
+use crate::handler::Handler;
+use crate::http::{preferred_media_type, Method, Request, Response};
+use crate::state::State;
+use std::error;
+use std::fmt;
+use std::fmt::Write;
+
+/// What serves a route
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteKind {
+    /// A handler registered with one of the `add_*route` functions
+    Handler,
+    /// Files served from the directory
+    StaticDir(String),
+    /// The default handler of the router
+    Default,
+}
+
+/// Description of a registered route, see `Router::routes` and `Server::routes`
+#[derive(Debug, Clone)]
+pub struct RouteInfo {
+    /// The method the route answers, `None` for routes answering any method
+    pub method: Option<Method>,
+    /// The path template the route was registered with
+    pub path: String,
+    pub name: Option<String>,
+    /// Names of the middlewares wrapping the route, outermost first
+    pub middlewares: Vec<String>,
+    /// Host patterns of the router, empty when the router serves every host
+    pub hosts: Vec<String>,
+    pub kind: RouteKind,
+}
+
+/// Handler listing a route table.
+/// Responds with JSON when the request accepts `application/json`, otherwise with plain text.
+///
+/// ```ignore
+/// let listing = RouteListing::new(server.routes());
+/// ops_router.add_route(Method::GET, "/_routes", listing)?;
+/// ```
+pub struct RouteListing {
+    routes: Vec<RouteInfo>,
+}
+
+impl RouteListing {
+    pub fn new(routes: Vec<RouteInfo>) -> Self {
+        RouteListing { routes }
+    }
+
+    fn render_text(&self) -> String {
+        let mut out = String::new();
+        for route in &self.routes {
+            let method = route.method.as_ref().map_or("*", |m| m.as_str());
+            let _ = write!(out, "{:<8} {}", method, route.path);
+            if let Some(name) = &route.name {
+                let _ = write!(out, " name={}", name);
+            }
+            if let RouteKind::StaticDir(dir) = &route.kind {
+                let _ = write!(out, " static={}", dir);
+            }
+            if route.kind == RouteKind::Default {
+                out.push_str(" default");
+            }
+            if !route.hosts.is_empty() {
+                let _ = write!(out, " hosts={}", route.hosts.join(","));
+            }
+            if !route.middlewares.is_empty() {
+                let _ = write!(out, " middlewares={}", route.middlewares.join(","));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn render_json(&self) -> String {
+        let entries: Vec<String> = self
+            .routes
+            .iter()
+            .map(|route| {
+                let kind = match &route.kind {
+                    RouteKind::Handler => "\"handler\"".to_owned(),
+                    RouteKind::StaticDir(dir) => format!("{{\"static_dir\":{}}}", json_string(dir)),
+                    RouteKind::Default => "\"default\"".to_owned(),
+                };
+                let middlewares: Vec<String> =
+                    route.middlewares.iter().map(|m| json_string(m)).collect();
+                let hosts: Vec<String> = route.hosts.iter().map(|h| json_string(h)).collect();
+                format!(
+                    "{{\"method\":{},\"path\":{},\"name\":{},\"middlewares\":[{}],\"hosts\":[{}],\"kind\":{}}}",
+                    route.method.as_ref().map_or("null".to_owned(), |m| json_string(m.as_str())),
+                    json_string(&route.path),
+                    route.name.as_deref().map_or("null".to_owned(), json_string),
+                    middlewares.join(","),
+                    hosts.join(","),
+                    kind
+                )
+            })
+            .collect();
+        format!("[{}]", entries.join(","))
+    }
+}
+
+impl Handler for RouteListing {
+    fn handle(&self, req: Request, _state: State) -> Response {
+        let accept = req.headers().get("Accept");
+        let json = preferred_media_type(accept, &["text/plain", "application/json"]) == Some("application/json");
+        let mut response = Response::ok();
+        if json {
+            response.insert_header("Content-Type".to_owned(), "application/json".to_owned());
+            response.body(self.render_json().into_bytes());
+        } else {
+            response.insert_header("Content-Type".to_owned(), "text/plain; charset=utf-8".to_owned());
+            response.body(self.render_text().into_bytes());
+        }
+        response
+    }
+}
+
+/// Quotes and escapes a string as a JSON string literal.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// How a newly registered route relates to an existing one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictKind {
+    /// Both routes match exactly the same paths
+    Duplicate,
+    /// Every path of the new route is already matched by the existing route, making it unreachable
+    Shadowed,
+    /// Some paths match both routes and are served by whichever was registered first
+    Ambiguous,
+}
+
+/// Error returned when a route can never be reached because of an earlier route
+#[derive(Debug, Clone)]
+pub struct RouteConflict {
+    pub method: Method,
+    pub path: String,
+    /// The path template of the earlier route
+    pub existing: String,
+    pub kind: ConflictKind,
+}
+
+impl fmt::Display for RouteConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ConflictKind::Duplicate => write!(
+                f,
+                "Route {} {} duplicates the existing route {}",
+                self.method, self.path, self.existing
+            ),
+            ConflictKind::Shadowed => write!(
+                f,
+                "Route {} {} is unreachable, every request is matched by the existing route {}",
+                self.method, self.path, self.existing
+            ),
+            ConflictKind::Ambiguous => write!(
+                f,
+                "Route {} {} overlaps the existing route {}, requests matching both go to {}",
+                self.method, self.path, self.existing, self.existing
+            ),
+        }
+    }
+}
+
+impl error::Error for RouteConflict {}
+
+#[derive(PartialEq)]
+enum Segment<'a> {
+    Param,
+    Literal(&'a str),
+    /// Literal text mixed with parameters, e.g. `{name}.txt`
+    Mixed(&'a str),
+}
+
+fn segments(template: &str) -> Vec<Segment<'_>> {
+    template
+        .split('/')
+        .map(|s| {
+            if s.starts_with('{') && s.ends_with('}') && s.matches('{').count() == 1 {
+                Segment::Param
+            } else if s.contains('{') {
+                Segment::Mixed(s)
+            } else {
+                Segment::Literal(s)
+            }
+        })
+        .collect()
+}
+
+/// Returns true if `general` matches every path that `specific` matches.
+fn covers(general: &[Segment], specific: &[Segment]) -> bool {
+    general.iter().zip(specific).all(|(g, s)| match (g, s) {
+        (Segment::Param, _) => true,
+        (g, s) => g == s,
+    })
+}
+
+fn overlaps(a: &[Segment], b: &[Segment]) -> bool {
+    a.iter().zip(b).all(|(a, b)| match (a, b) {
+        (Segment::Param, _) | (_, Segment::Param) => true,
+        // Mixed segments are only compared textually
+        (a, b) => a == b,
+    })
+}
+
+/// Checks how the route template `path` relates to the earlier registered template `existing`.
+/// Parameters match a single path segment, so templates with a different number of segments never conflict.
+pub(crate) fn conflict(path: &str, existing: &str) -> Option<ConflictKind> {
+    let new = segments(path);
+    let old = segments(existing);
+    if new.len() != old.len() {
+        return None;
+    }
+    match (covers(&old, &new), covers(&new, &old)) {
+        (true, true) => Some(ConflictKind::Duplicate),
+        (true, false) => Some(ConflictKind::Shadowed),
+        _ if overlaps(&new, &old) => Some(ConflictKind::Ambiguous),
+        _ => None,
+    }
+}
+
+#[derive(PartialEq)]
+enum HostLabel<'a> {
+    Param,
+    Literal(&'a str),
+}
+
+/// Splits a host pattern into whether it starts with `*`, matching one or more labels, and its other labels.
+fn host_labels(pattern: &str) -> (bool, Vec<HostLabel<'_>>) {
+    let (wildcard, rest) = match pattern.strip_prefix("*.") {
+        Some(rest) => (true, rest),
+        None => (false, pattern),
+    };
+    let labels = rest
+        .split('.')
+        .map(|l| {
+            if l.starts_with('{') && l.ends_with('}') {
+                HostLabel::Param
+            } else {
+                HostLabel::Literal(l)
+            }
+        })
+        .collect();
+    (wildcard, labels)
+}
+
+/// Returns true if some host matches both host patterns.
+/// Labels are compared from the right, where a leading `*` can take up any number of extra labels.
+pub(crate) fn hosts_overlap(a: &str, b: &str) -> bool {
+    let (a_wildcard, a) = host_labels(a);
+    let (b_wildcard, b) = host_labels(b);
+    let labels_match = a.iter().rev().zip(b.iter().rev()).all(|(a, b)| match (a, b) {
+        (HostLabel::Param, _) | (_, HostLabel::Param) => true,
+        (a, b) => a == b,
+    });
+    labels_match
+        && match (a_wildcard, b_wildcard) {
+            (false, false) => a.len() == b.len(),
+            (true, false) => b.len() > a.len(),
+            (false, true) => a.len() > b.len(),
+            (true, true) => true,
+        }
+}
+
+/// Returns true if the host pattern `general` matches every host that `specific` matches.
+pub(crate) fn host_covers(general: &str, specific: &str) -> bool {
+    let (general_wildcard, general) = host_labels(general);
+    let (specific_wildcard, specific) = host_labels(specific);
+    let labels_covered = general.iter().rev().zip(specific.iter().rev()).all(|(g, s)| match (g, s) {
+        (HostLabel::Param, _) => true,
+        (g, s) => g == s,
+    });
+    labels_covered
+        && match (general_wildcard, specific_wildcard) {
+            (false, false) => general.len() == specific.len(),
+            (false, true) => false,
+            (true, false) => specific.len() > general.len(),
+            (true, true) => specific.len() >= general.len(),
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new route template, the earlier template and how they conflict
+    type Conflict = (&'static str, &'static str, Option<ConflictKind>);
+
+    const CONFLICTS: &[Conflict] = &[
+        ("/users/{id}", "/users/{name}", Some(ConflictKind::Duplicate)),
+        ("/users", "/users", Some(ConflictKind::Duplicate)),
+        ("/users/me", "/users/{id}", Some(ConflictKind::Shadowed)),
+        ("/users/{id}/posts", "/{kind}/{id}/posts", Some(ConflictKind::Shadowed)),
+        ("/files/{name}.txt", "/files/{name}", Some(ConflictKind::Shadowed)),
+        ("/users/{id}", "/users/me", Some(ConflictKind::Ambiguous)),
+        ("/{kind}/me", "/users/{id}", Some(ConflictKind::Ambiguous)),
+        ("/users/{id}", "/posts/{id}", None),
+        ("/users/{id}", "/users", None),
+        ("/users/{id}", "/users/{id}/posts", None),
+        ("/users/{id}/", "/users/{id}", None),
+        ("/files/{name}.txt", "/files/{name}.md", None),
+    ];
+
+    #[test]
+    fn route_templates_conflict() {
+        for (path, existing, expected) in CONFLICTS {
+            assert_eq!(conflict(path, existing), *expected, "{} after {}", path, existing);
+        }
+    }
+
+    /// Two host patterns, whether some host matches both, and whether the first matches every host of the second
+    type Hosts = (&'static str, &'static str, bool, bool);
+
+    const HOSTS: &[Hosts] = &[
+        ("example.com", "example.com", true, true),
+        ("example.com", "example.org", false, false),
+        // A wildcard takes at least one label
+        ("*.example.com", "example.com", false, false),
+        ("*.example.com", "api.example.com", true, true),
+        ("*.example.com", "v1.api.example.com", true, true),
+        ("*.example.com", "*.api.example.com", true, true),
+        ("*.api.example.com", "*.example.com", true, false),
+        ("api.example.com", "*.example.com", true, false),
+        ("*.example.com", "*.example.org", false, false),
+        // A captured label takes exactly one label
+        ("{tenant}.example.com", "api.example.com", true, true),
+        ("{tenant}.example.com", "v1.api.example.com", false, false),
+        ("{tenant}.example.com", "*.example.com", true, false),
+        ("*.example.com", "{tenant}.example.com", true, true),
+        ("api.example.com", "{tenant}.example.com", true, false),
+        ("{tenant}.example.com", "api.{region}.com", true, false),
+        ("{tenant}.example.com", "{region}.example.com", true, true),
+    ];
+
+    #[test]
+    fn host_patterns_overlap() {
+        for (a, b, overlap, covers) in HOSTS {
+            assert_eq!(hosts_overlap(a, b), *overlap, "{} overlapping {}", a, b);
+            assert_eq!(hosts_overlap(b, a), *overlap, "{} overlapping {}", b, a);
+            assert_eq!(host_covers(a, b), *covers, "{} covering {}", a, b);
+        }
+    }
+}
@@ -1,177 +1,257 @@
-use crate::error::Error;
+use crate::error::ParseError;
 use crate::http::Method;
 use crate::http::Request;
 use crate::http::Response;
-use crate::router::Router;
+use crate::http::StatusCode;
+use crate::http::Version;
+use crate::parser::Connection;
+use crate::handler::Handler;
+use crate::router::{allow_header, Routed, Router};
+use crate::routes::RouteInfo;
 use log::error;
 use log::info;
 
 use may::net::TcpListener;
 use rustls::server::ServerConfig;
-use std::collections::HashMap;
+use std::any::Any;
+use std::error::Error as StdError;
 use std::io::{Read, Write};
-use std::str::from_utf8;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
 
 const BUFFER_SIZE: usize = 1024 * 30;
 
-fn handle_client(mut stream: may::net::TcpStream, router: Vec<Router>) {
-    let mut buffer = [0; BUFFER_SIZE];
-    loop {
-        match stream.read(&mut buffer) {
-            Ok(0) => return, // Connection closed
-            Ok(bytes_read) => match parse_request(&buffer[0..bytes_read]) {
-                Ok(request) => {
-                    let mut response = None;
-                    for r in &router {
-                        let resp = r.handle_request(request.clone());
-                        if resp.is_some() {
-                            response = resp.clone();
-                            break;
-                        }
-                    }
-                    let response = match response {
-                        Some(r) => r,
-                        None => Response::not_found(),
-                    };
-                    if let Err(e) = stream.write_all(&response.format()) {
-                        error!("Failed to write to stream: {}", e);
-                        return;
-                    }
+type BadRequestHandler = dyn Fn(&ParseError) -> Response + Send + Sync;
+type PanicHook = dyn Fn(&HandlerPanic) + Send + Sync;
+
+/// A panic caught while answering a request, see `Server::set_panic_hook`
+#[derive(Debug, Clone)]
+pub struct HandlerPanic {
+    pub method: Method,
+    pub path: String,
+    pub message: String,
+}
+
+/// Handlers answering the requests no router answers
+#[derive(Clone, Default)]
+struct Fallbacks {
+    not_found: Option<Arc<dyn Handler>>,
+    method_not_allowed: Option<Arc<dyn Handler>>,
+    bad_request: Option<Arc<BadRequestHandler>>,
+    panic_hook: Option<Arc<PanicHook>>,
+}
+
+impl Fallbacks {
+    fn bad_request(&self, error: &ParseError) -> Response {
+        match &self.bad_request {
+            // Errors with their own status, such as 413, keep it
+            Some(handler) if matches!(error.status(), StatusCode::BadRequest) => handler(error),
+            _ => error.http_response(),
+        }
+    }
+    /// Logs and reports a panic caught while answering a request, returning the response to send instead
+    fn panicked(&self, method: Method, path: String, payload: Box<dyn Any + Send>) -> Response {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|m| m.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Box<dyn Any>".to_owned());
+        error!("Handler panicked on {} {}: {}", method, path, message);
+        if let Some(hook) = &self.panic_hook {
+            hook(&HandlerPanic { method, path, message });
+        }
+        Response::internal_server_error()
+    }
+}
+
+/// Routes the request to the first router serving it.
+/// Virtual host routers are tried before the routers serving every host.
+fn dispatch(routers: &[Router], fallbacks: &Fallbacks, mut request: Request) -> Response {
+    let host = request.host();
+    if let Some(server_name) = request.server_name() {
+        // The Host header must name the host the TLS connection was established for
+        if host.as_deref() != Some(server_name.to_ascii_lowercase().as_str()) {
+            return Response::new(StatusCode::MisdirectedRequest);
+        }
+    }
+    let mut allowed: Vec<Method> = Vec::new();
+    // The fallbacks get the state of the first router with routes for the path, or else serving the host
+    let mut state = None;
+    for r in dispatch_order(routers) {
+        request = match r.route(request) {
+            Routed::Response(response) => return response,
+            Routed::MethodNotAllowed(req, methods) => {
+                if allowed.is_empty() {
+                    state = Some(r.state().clone());
                 }
-                Err(e) => {
-                    error!("Failed to parse request");
-                    let response_bytes = e.http_response();
-                    if let Err(e) = stream.write_all(&response_bytes.format()) {
-                        error!("Failed to write to stream: {}", e);
-                        return;
+                for method in methods {
+                    if !allowed.contains(&method) {
+                        allowed.push(method);
                     }
                 }
-            },
-            Err(e) => {
-                error!("Failed to read from stream: {}", e);
-                return;
+                req
             }
-        }
+            Routed::NotFound(req) => req,
+        };
+    }
+    let serving = dispatch_order(routers).find(|r| r.match_host(host.as_deref()).is_some());
+    if serving.is_none() && request.server_name().is_some() {
+        return Response::new(StatusCode::MisdirectedRequest);
+    }
+    let state = state
+        .or_else(|| serving.map(|r| r.state().clone()))
+        .unwrap_or_default();
+
+    let accept = request.headers().get("Accept").map(str::to_owned);
+    if !allowed.is_empty() {
+        let mut response = match &fallbacks.method_not_allowed {
+            Some(handler) => handler.handle(request, state),
+            None => Response::error_page(StatusCode::MethodNotAlloed, accept.as_deref()),
+        };
+        response.insert_header("Allow".to_owned(), allow_header(&allowed));
+        return response;
+    }
+    match &fallbacks.not_found {
+        Some(handler) => handler.handle(request, state),
+        None => Response::error_page(StatusCode::NotFound, accept.as_deref()),
     }
 }
-fn handle_client_tls(
-    mut stream: may::net::TcpStream,
-    router: Vec<Router>,
-    tls_config: Arc<ServerConfig>,
-) {
-    let mut buffer = vec![0; BUFFER_SIZE];
 
-    let mut tls_conn =
-        rustls::ServerConnection::new(tls_config).expect("Cannot create TLS connection");
-    let mut tlsstream = rustls::Stream::new(&mut tls_conn, &mut stream);
+/// The routers in the order they are tried, virtual hosts first
+fn dispatch_order(routers: &[Router]) -> impl Iterator<Item = &Router> {
+    let virtual_hosts = routers.iter().filter(|r| r.is_virtual_host());
+    let defaults = routers.iter().filter(|r| !r.is_virtual_host());
+    virtual_hosts.chain(defaults)
+}
 
+/// Everything a connection needs to answer requests, shared by all connections
+struct Shared {
+    routers: Vec<Router>,
+    fallbacks: Fallbacks,
+    max_body_size: usize,
+    strict: bool,
+}
+
+/// Serves the requests of a connection until it is closed
+fn serve<S: Read + Write>(stream: &mut S, shared: &Shared, server_name: Option<String>, remote_addr: Option<SocketAddr>) {
+    let mut connection = Connection::new(BUFFER_SIZE, shared.max_body_size, shared.strict);
     loop {
-        match tlsstream.read(&mut buffer) {
-            Ok(0) => return, // Connection closed
-            Ok(bytes_read) => match parse_request(&buffer[0..bytes_read]) {
-                Ok(request) => {
-                    let mut response = None;
-                    for r in &router {
-                        let resp = r.handle_request(request.clone());
-                        if resp.is_some() {
-                            response = resp.clone();
-                            break;
-                        }
-                    }
-                    let response = match response {
-                        Some(r) => r,
-                        None => Response::not_found(),
-                    };
-                    if let Err(e) = tlsstream.write_all(&response.format()) {
-                        error!("Failed to write to stream: {}", e);
-                        return;
-                    }
+        let request = match connection.next_request(stream) {
+            Ok(Some(request)) => request,
+            Ok(None) => return, // Connection closed
+            Err(e) => {
+                error!("Failed to read from stream: {}", e);
+                return;
+            }
+        };
+        match request {
+            Ok(mut request) => {
+                request.set_server_name(server_name.clone());
+                request.set_remote_addr(remote_addr);
+                let head = *request.method() == Method::HEAD;
+                let close = closes_connection(&request) || connection.must_close();
+                // A panicking handler fails its request only, the request was read in full so the connection stays usable
+                let method = request.method().clone();
+                let path = request.uri().path.clone();
+                let dispatched = panic::catch_unwind(AssertUnwindSafe(|| {
+                    dispatch(&shared.routers, &shared.fallbacks, request)
+                }));
+                let mut response = match dispatched {
+                    Ok(response) => response,
+                    Err(payload) => shared.fallbacks.panicked(method, path, payload),
+                };
+                if close {
+                    response.insert_header("Connection".to_owned(), "close".to_owned());
                 }
-                Err(e) => {
-                    error!("Failed to parse request");
-                    let response_bytes = e.http_response();
-                    if let Err(e) = tlsstream.write_all(&response_bytes.format()) {
-                        error!("Failed to write to stream: {}", e);
-                        return;
-                    }
+                let response_bytes = if head {
+                    response.format_head()
+                } else {
+                    response.format()
+                };
+                if let Err(e) = stream.write_all(&response_bytes) {
+                    error!("Failed to write to stream: {}", e);
+                    return;
+                }
+                if close {
+                    return;
                 }
-            },
+            }
             Err(e) => {
-                error!("Failed to read from stream: {}", e);
+                // The end of the request is unknown, so the connection can't be reused
+                error!("Failed to parse request: {}", e);
+                let mut response = shared.fallbacks.bad_request(&e);
+                response.insert_header("Connection".to_owned(), "close".to_owned());
+                if let Err(e) = stream.write_all(&response.format()) {
+                    error!("Failed to write to stream: {}", e);
+                }
                 return;
             }
         }
     }
 }
 
-fn parse_request(buffer: &[u8]) -> Result<Request, Error> {
-    let request_str = match from_utf8(buffer) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("{:?}", e);
-            return Err(Error::BadRequest);
-        }
-    };
-    let mut request_lines = request_str.split("\r\n");
-
-    if request_lines.clone().count() == 0 {
-        return Err(Error::BadRequest);
+/// HTTP/1.1 connections are persistent unless closed by the client, HTTP/1.0 connections only when asked for
+fn closes_connection(request: &Request) -> bool {
+    let connection = request
+        .headers()
+        .get("Connection")
+        .map(|v| v.to_ascii_lowercase());
+    match request.version() {
+        Version::HTTP1_0 => connection.as_deref() != Some("keep-alive"),
+        _ => connection.as_deref() == Some("close"),
     }
+}
 
-    let request_parts = match request_lines.next() {
-        Some(s) => s,
-        None => return Err(Error::BadRequest),
-    };
-    let parts: Vec<&str> = request_parts.split_whitespace().collect();
-    if parts.len() < 3 {
-        return Err(Error::BadRequest);
-    }
+fn handle_client(mut stream: may::net::TcpStream, shared: Arc<Shared>) {
+    let remote_addr = stream.peer_addr().ok();
+    serve(&mut stream, &shared, None, remote_addr);
+}
 
-    let method = match parts[0].parse::<Method>() {
-        Ok(m) => m,
-        Err(e) => {
-            error!("{:?}", e);
-            return Err(Error::BadRequest);
-        }
-    };
-    let uri = parts[1];
-
-    let mut kv_headers = HashMap::new();
-    for value in request_lines.clone() {
-        request_lines.next();
-        if value == "" {
-            break;
+fn handle_client_tls(
+    mut stream: may::net::TcpStream,
+    shared: Arc<Shared>,
+    tls_config: Arc<ServerConfig>,
+) {
+    let mut tls_conn =
+        rustls::ServerConnection::new(tls_config).expect("Cannot create TLS connection");
+    // Complete the handshake first, making the server name (SNI) known before the first request
+    while tls_conn.is_handshaking() {
+        if let Err(e) = tls_conn.complete_io(&mut stream) {
+            error!("TLS handshake failed: {}", e);
+            return;
         }
-        let kv: Vec<_> = value.split(": ").collect();
-        kv_headers.insert(kv[0], kv[1]);
     }
-
-    let body = match request_lines.next() {
-        Some(s) => s,
-        None => return Err(Error::BadRequest),
-    };
-
-    let mut request = Request::new(method);
-    request.set_uri(uri);
-    for (k, v) in kv_headers {
-        request.insert_header(k, v);
-    }
-    request.body(body.as_bytes().to_vec());
-    Ok(request)
+    let server_name = tls_conn.server_name().map(str::to_owned);
+    let remote_addr = stream.peer_addr().ok();
+    let mut tlsstream = rustls::Stream::new(&mut tls_conn, &mut stream);
+    serve(&mut tlsstream, &shared, server_name, remote_addr);
 }
+
 /// The server
 pub struct Server {
     workers: usize,
     stack_size: usize,
     router: Vec<Router>,
+    fallbacks: Fallbacks,
+    max_body_size: usize,
+    strict: bool,
+}
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
 }
+
 impl Server {
     pub fn new() -> Self {
         Server {
             workers: 4,
             stack_size: 256 * 1024,
             router: Vec::new(),
+            fallbacks: Fallbacks::default(),
+            max_body_size: 10 * 1024 * 1024,
+            strict: true,
         }
     }
     /// Add threads/workers
@@ -184,22 +264,91 @@ impl Server {
     pub fn stack(&mut self, size: usize) {
         self.stack_size = size;
     }
+    /// Set the largest request body accepted, larger requests are answered with 413
+    /// Default 10 MB
+    pub fn max_body_size(&mut self, size: usize) {
+        self.max_body_size = size;
+    }
+    /// Set whether requests are parsed strictly.
+    /// In strict mode requests a proxy might read differently than the server are rejected with 400:
+    /// lines ended by a bare LF, both Content-Length and Transfer-Encoding, chunked HTTP/1.0 requests,
+    /// whitespace before chunk extensions and paths with characters URIs don't allow.
+    /// Otherwise they are accepted as RFC 9112 allows, closing the connection after ambiguous requests.
+    /// Default on
+    pub fn strict_parsing(&mut self, strict: bool) {
+        self.strict = strict;
+    }
     ///Add a router to the server
-    ///Additional routers can be added to the server.
-    pub fn add_router(&mut self, router: Router) {
+    ///Additional routers can be added to the server and are tried in the order they were added,
+    ///routers bound to hosts with `Router::add_host` before the ones serving every host.
+    ///Returns an error if the router configuration is invalid, see `Router::validate`,
+    ///or if a route can never be reached because of a router tried before it.
+    pub fn add_router(&mut self, router: Router) -> Result<(), Box<dyn StdError>> {
+        router.validate()?;
+        // Conflicts are checked in the order routers are tried, see `dispatch`
+        for other in &self.router {
+            if router.is_virtual_host() && !other.is_virtual_host() {
+                other.check_conflicts(&router)?;
+            } else {
+                router.check_conflicts(other)?;
+            }
+        }
         self.router.push(router);
+        Ok(())
+    }
+    /// Set the handler answering requests no router has a route for.
+    /// It is called with the state of the first router serving the host of the request.
+    /// Without it a 404 Not Found page is sent, as JSON, HTML or text depending on the Accept header.
+    pub fn set_not_found_handler(&mut self, handler: impl Handler + 'static) {
+        self.fallbacks.not_found = Some(Arc::new(handler));
+    }
+    /// Set the handler answering requests for a path the routers serve, but not for the method of the request.
+    /// It is called with the state of the first router serving the path.
+    /// The Allow header listing the methods of the path is added to its response.
+    pub fn set_method_not_allowed_handler(&mut self, handler: impl Handler + 'static) {
+        self.fallbacks.method_not_allowed = Some(Arc::new(handler));
+    }
+    /// Set the handler answering requests that can't be parsed with 400 Bad Request.
+    /// Requests rejected with another status, such as 413 Payload Too Large or 431 Request Header Fields Too Large,
+    /// are still answered with that status.
+    pub fn set_bad_request_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(&ParseError) -> Response + Send + Sync + 'static,
+    {
+        self.fallbacks.bad_request = Some(Arc::new(handler));
+    }
+    /// Set a hook called with every panic caught in a handler or middleware, e.g. to report it.
+    /// Panics are always logged and answered with 500 Internal Server Error.
+    pub fn set_panic_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&HandlerPanic) + Send + Sync + 'static,
+    {
+        self.fallbacks.panic_hook = Some(Arc::new(hook));
+    }
+    /// Returns the routes of every router added to the server, in the order they are tried.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.router.iter().flat_map(|r| r.routes()).collect()
+    }
+    fn shared(&self) -> Arc<Shared> {
+        Arc::new(Shared {
+            routers: self.router.clone(),
+            fallbacks: self.fallbacks.clone(),
+            max_body_size: self.max_body_size,
+            strict: self.strict,
+        })
     }
     /// Start the server.
     pub fn run(&self, addr: &str) -> std::io::Result<()> {
         may::config().set_workers(self.workers);
         may::config().set_stack_size(self.stack_size);
 
+        let shared = self.shared();
         let listener = TcpListener::bind(addr)?;
         while let Ok((stream, saddr)) = listener.accept() {
-            let router = self.router.clone();
+            let shared = shared.clone();
             info!("Connection {:?}", saddr);
             go!(move || {
-                handle_client(stream, router.clone());
+                handle_client(stream, shared);
             });
         }
         Ok(())
@@ -209,16 +358,45 @@ impl Server {
         may::config().set_workers(self.workers);
         may::config().set_stack_size(self.stack_size * 1024);
 
+        let shared = self.shared();
         let listener = TcpListener::bind(addr)?;
 
         while let Ok((stream, saddr)) = listener.accept() {
             let tls_config = tls_config.clone();
-            let router = self.router.clone();
+            let shared = shared.clone();
             info!("Connection {:?}", saddr);
             go!(move || {
-                handle_client_tls(stream, router.clone(), tls_config.clone());
+                handle_client_tls(stream, shared, tls_config);
             });
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_request_handler_only_answers_bad_requests() {
+        let fallbacks = Fallbacks {
+            bad_request: Some(Arc::new(|error: &ParseError| {
+                let mut response = Response::new(StatusCode::BadRequest);
+                response.body(error.to_string().into_bytes());
+                response
+            })),
+            ..Fallbacks::default()
+        };
+        let response = fallbacks.bad_request(&ParseError::DuplicateHost);
+        assert_eq!(response.status().as_u16(), 400);
+        assert!(response.format().ends_with(b"Duplicate Host header"));
+        for (error, status) in [
+            (ParseError::HeaderTooLarge, 431),
+            (ParseError::PayloadTooLarge, 413),
+            (ParseError::UnsupportedTransferEncoding, 501),
+            (ParseError::VersionNotSupported, 505),
+        ] {
+            assert_eq!(fallbacks.bad_request(&error).status().as_u16(), status, "{:?}", error);
+        }
+    }
+}
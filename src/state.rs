@@ -1,28 +1,60 @@
-use std::any::Any;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
 use std::sync::Arc;
 
 
-///Trait required to be implmented for the struct expected to be the shared state.
+/// Shared state of a router, holding one value per type.
+/// Cloning is cheap, the values are shared.
 #[derive(Clone,Debug)]
 pub struct State{
-    inner: Arc<dyn Any + Send + Sync+ 'static>
+    inner: Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>
 }
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl State{
     pub fn new()->Self{
 
-        State{inner:Arc::new(())}
+        State{inner:Arc::new(HashMap::new())}
     }
+    /// Adds a value, replacing the earlier value of the same type
     pub fn set<T: 'static + Send + Sync>(&mut self, obj:T){
-        let inner = Arc::new(obj);
-        self.inner= inner;
+        Arc::make_mut(&mut self.inner).insert(TypeId::of::<T>(), Arc::new(obj));
     }
+    /// Returns the value of the type, `None` if there is none
     pub fn inner<T:'static + std::marker::Sync+ std::marker::Send>(&self) -> Option<Arc<T>>{
-        let inner = self.inner.clone();
-        match inner.downcast::<T>() {
-            Ok(i) => {
-                let ret = i.clone();
-                return Some(ret)},
-            Err(_) => None,
+        let value = self.inner.get(&TypeId::of::<T>())?.clone();
+        value.downcast::<T>().ok()
+    }
+    /// Returns the value of the type.
+    /// Panics if there is none, which `Router::validate` rules out for the types handlers require.
+    pub fn get<T: 'static + Send + Sync>(&self) -> Arc<T> {
+        match self.inner::<T>() {
+            Some(value) => value,
+            None => panic!("No state of type {}", std::any::type_name::<T>()),
+        }
+    }
+    /// Returns true if there is a value of the type
+    pub fn contains(&self, state_type: &StateType) -> bool {
+        self.inner.contains_key(&state_type.id)
+    }
+}
+
+/// A state type a handler requires, see `Handler::required_state`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StateType {
+    pub id: TypeId,
+    pub name: &'static str,
+}
+
+impl StateType {
+    pub fn of<T: 'static>() -> Self {
+        StateType {
+            id: TypeId::of::<T>(),
+            name: std::any::type_name::<T>(),
         }
     }
-}
\ No newline at end of file
+}
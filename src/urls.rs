@@ -0,0 +1,91 @@
+use crate::http::percent::{encode_component, encode_form};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+
+/// Errors returned when generating a URL for a named route
+#[derive(Debug, Clone, PartialEq)]
+pub enum UrlError {
+    /// No route has been registered with the name
+    UnknownRoute(String),
+    /// The route template has a `{param}` that was not supplied
+    MissingParameter { route: String, param: String },
+}
+
+impl fmt::Display for UrlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UrlError::UnknownRoute(name) => write!(f, "No route named \"{}\"", name),
+            UrlError::MissingParameter { route, param } => write!(
+                f,
+                "Missing parameter \"{}\" for route \"{}\"",
+                param, route
+            ),
+        }
+    }
+}
+
+impl error::Error for UrlError {}
+
+/// Maps route names to their path templates for reverse routing.
+/// Cheap to clone, every request served by a router carries a copy.
+#[derive(Clone, Debug, Default)]
+pub struct UrlMap {
+    templates: Arc<HashMap<String, String>>,
+}
+
+impl UrlMap {
+    pub fn new() -> Self {
+        UrlMap::default()
+    }
+    pub(crate) fn insert(&mut self, name: &str, template: &str) {
+        Arc::make_mut(&mut self.templates).insert(name.to_owned(), template.to_owned());
+    }
+    /// Returns true if a route with the name is known
+    pub fn contains(&self, name: &str) -> bool {
+        self.templates.contains_key(name)
+    }
+    /// Builds the URL for a named route.
+    /// Parameters matching a `{param}` in the template are substituted into the path,
+    /// the remaining ones are appended as the query string. All values are percent-encoded.
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        let template = self
+            .templates
+            .get(name)
+            .ok_or_else(|| UrlError::UnknownRoute(name.to_owned()))?;
+
+        let mut url = String::with_capacity(template.len());
+        let mut used = Vec::new();
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            url.push_str(&rest[..start]);
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            let param = &rest[start + 1..end];
+            match params.iter().find(|(k, _)| *k == param) {
+                Some((k, v)) => {
+                    url.push_str(&encode_component(v));
+                    used.push(*k);
+                }
+                None => {
+                    return Err(UrlError::MissingParameter {
+                        route: name.to_owned(),
+                        param: param.to_owned(),
+                    })
+                }
+            }
+            rest = &rest[end + 1..];
+        }
+        url.push_str(rest);
+
+        let query = encode_form(params.iter().filter(|(k, _)| !used.contains(k)).copied());
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+        Ok(url)
+    }
+}